@@ -1,4 +1,4 @@
-use crate::consts::KERNEL_STACK_SIZE;
+use crate::consts::{HART_ID_SLOT, KERNEL_STACK_SIZE, MAX_HARTS};
 use crate::trap::TrapFrame;
 
 macro_rules! clear_reg {
@@ -16,10 +16,9 @@ pub unsafe extern "C" fn entry() -> ! {
     core::arch::asm!(
         "fence.i",
 
-        // Clear everything except ra, a0(hartid) and a1(fdt addr)
+        // Clear everything except ra, a0(hartid) and a1(fdt addr). tp is set below
         clear_reg!(sp),
         clear_reg!(gp),
-        clear_reg!(tp),
         clear_reg!(t0),
         clear_reg!(t1),
         clear_reg!(t2),
@@ -48,16 +47,32 @@ pub unsafe extern "C" fn entry() -> ! {
         clear_reg!(t5),
         clear_reg!(t6),
 
-        // Setup stack
+        // Harts beyond MAX_HARTS have no stack to use, park them before touching memory
+        "li t0, {max_harts}",
+        "bgeu a0, t0, 2f",
+
+        // Setup the stack of this hart, and its id in tp
+        "mv tp, a0",
         "la sp, INIT_STACK",
-        "li t0, {}",
-        "add sp, sp, t0",
+        "li t0, {size}",
+        "addi t1, a0, 1",
+        "mul t1, t1, t0",
+        "add sp, sp, t1",
+        "addi sp, sp, -{reserve}",
+        "sd a0, {tf}(sp)",
 
         // Jump to boot
         "j boot",
 
-        // Reserve for one trap frame at the top
-        const KERNEL_STACK_SIZE - core::mem::size_of::<TrapFrame>(),
+        "2:",
+        "wfi",
+        "j 2b",
+
+        // Reserve for the hart id and one trap frame at the top
+        size = const KERNEL_STACK_SIZE,
+        reserve = const HART_ID_SLOT + core::mem::size_of::<TrapFrame>(),
+        tf = const core::mem::size_of::<TrapFrame>(),
+        max_harts = const MAX_HARTS,
         options(noreturn),
     )
 }
@@ -0,0 +1,396 @@
+//! Shared-memory channels
+//!
+//! A channel is a pair of single-producer single-consumer rings shared between a client and a
+//! service: requests flow from the client to the service, responses flow back. The layout is
+//!
+//! - one header page holding the version, geometry and ring indices
+//! - the request slots, starting on the next page
+//! - the response slots, starting on the page after the request slots
+//!
+//! The same type is used by the kernel, by kernel services and by the vDSO, so everything used
+//! on the data path is `#[inline(always)]`: the vDSO cannot call into kernel `.text`.
+//! `user/channel.h` mirrors this layout for C programs.
+
+use core::cell::UnsafeCell;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use alloc::sync::Arc;
+
+use crate::consts::PAGE_SIZE;
+use crate::mem::addr::{PhysAddr, PhysPageNum};
+use crate::mem::{Frame, OutOfMemory, SharedFrames};
+use crate::sched::WaitKey;
+
+pub const CHANNEL_MAGIC: u32 = u32::from_le_bytes(*b"CHAN");
+pub const CHANNEL_VERSION: u32 = 1;
+
+#[repr(C)]
+pub struct RingIndices {
+    /// Next slot to consume. Only advanced by the consumer
+    pub head: AtomicU32,
+    /// Next slot to produce. Only advanced by the producer
+    pub tail: AtomicU32,
+}
+
+#[repr(C, align(4096))]
+pub struct ChannelHeader {
+    pub magic: u32,
+    pub version: u32,
+    pub capacity: u32,
+    pub req_size: u32,
+    pub resp_size: u32,
+
+    pub req: RingIndices,
+    pub resp: RingIndices,
+
+    pub client_sleeping: AtomicBool,
+    pub server_sleeping: AtomicBool,
+    pub closed: AtomicBool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+impl Side {
+    #[inline(always)]
+    pub fn peer(self) -> Self {
+        match self {
+            Side::Client => Side::Server,
+            Side::Server => Side::Client,
+        }
+    }
+}
+
+impl ChannelHeader {
+    /// Set by a side before it blocks waiting on the channel, cleared by the doorbell that wakes it
+    #[inline(always)]
+    pub fn sleeping(&self, side: Side) -> &AtomicBool {
+        match side {
+            Side::Client => &self.client_sleeping,
+            Side::Server => &self.server_sleeping,
+        }
+    }
+}
+
+/// Default message format, two machine words
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Message {
+    pub ident: u64,
+    pub arg: u64,
+}
+
+/// Operation codes of `Sqe::opcode`. A service completes operations it does not implement with
+/// `SysError::Unsupported`
+pub mod op {
+    /// Completes right away. Useful as a barrier: completions are posted in submission order
+    /// unless a service documents otherwise
+    pub const NOP: u8 = 0;
+    /// Write the `len` bytes packed little-endian into `args`
+    pub const WRITE: u8 = 1;
+    /// Read at most `len` (up to 8) bytes, returned packed in `Cqe::value`. `res` holds the count
+    pub const READ: u8 = 2;
+    /// Complete once `args[0]` ticks of the `time` CSR have passed
+    pub const TIMEOUT: u8 = 3;
+    /// Write `len` bytes from `args[0]`, an address in the service. The client lends the buffer
+    /// with a grant and revokes it after the completion. Fails with `SysError::InvalidArgument`
+    /// if the buffer is not all lent
+    pub const WRITE_BUF: u8 = 4;
+}
+
+/// `Sqe::flags`: do not post a completion for this entry
+pub const SQE_SKIP_CQE: u8 = 1 << 0;
+
+/// Submission queue entry, one operation for a service
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Sqe {
+    pub opcode: u8,
+    pub flags: u8,
+    pub _pad: u16,
+    pub len: u32,
+    /// Chosen by the client and copied into the completion
+    pub user_data: u64,
+    pub args: [u64; 6],
+}
+
+impl Sqe {
+    /// Max payload of an `op::WRITE` entry
+    pub const INLINE_MAX: usize = 6 * size_of::<u64>();
+
+    /// A write of the first `INLINE_MAX` bytes at most of `data`
+    #[inline(always)]
+    pub fn write(data: &[u8], flags: u8, user_data: u64) -> Self {
+        let len = data.len().min(Self::INLINE_MAX);
+        let mut args = [0u64; 6];
+        // Shifted in one by one: a copy loop could be lowered to memcpy, which the vDSO can't call
+        let mut i = 0;
+        while i < len {
+            args[i / 8] |= (data[i] as u64) << (i % 8 * 8);
+            i += 1;
+        }
+
+        Self {
+            opcode: op::WRITE,
+            flags,
+            _pad: 0,
+            len: len as u32,
+            user_data,
+            args,
+        }
+    }
+
+    /// Byte `idx` of the inline payload
+    #[inline(always)]
+    pub fn payload(&self, idx: usize) -> u8 {
+        (self.args[idx / 8] >> (idx % 8 * 8)) as u8
+    }
+
+    #[inline(always)]
+    pub fn wants_cqe(&self) -> bool {
+        self.flags & SQE_SKIP_CQE == 0
+    }
+}
+
+/// Completion queue entry
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Cqe {
+    pub user_data: u64,
+    /// Non-negative on success, otherwise a `SysError` code
+    pub res: i64,
+    pub value: u64,
+}
+
+/// Posted by the kernel to a service's accept channel for every new connection
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Accept {
+    /// Where the new channel is mapped in the service's address space
+    pub base: u64,
+    /// Handle to the new channel in the service
+    pub handle: u64,
+    /// Handle to the client process in the service
+    pub process: u64,
+    /// Pid of the client, for logging. Kernel services also use it to wake the client
+    pub pid: u64,
+}
+
+pub type AcceptChannel = Channel<Accept, Message, 64>;
+
+/// Kernel bookkeeping for one end of a channel mapped into a process.
+/// Holds a reference to the channel pages, so the header stays valid while the endpoint lives
+pub struct Endpoint {
+    /// Where the channel is mapped in the owning process
+    pub base: usize,
+    pub frames: Arc<SharedFrames>,
+    pub side: Side,
+    /// Pid of the process on the other end, 0 for the kernel
+    pub peer: usize,
+}
+
+impl Endpoint {
+    pub fn header(&self) -> &ChannelHeader {
+        let addr: PhysAddr = self.frames.ppn(0).into();
+        unsafe { &*(addr.0 as *const ChannelHeader) }
+    }
+
+    pub fn wait_key(&self) -> WaitKey {
+        WaitKey::Channel(self.frames.ppn(0).0)
+    }
+
+    /// Whether a wait on this end should still block: the sleeping flag is set, so no doorbell
+    /// arrived since it was set, and the channel is still open
+    pub fn armed(&self) -> bool {
+        let hdr = self.header();
+        hdr.sleeping(self.side).load(Ordering::SeqCst) && !hdr.closed.load(Ordering::SeqCst)
+    }
+
+    /// Consume the sleeping flag of the other end. Returns true if the peer has to be woken up
+    pub fn notify_peer(&self) -> bool {
+        self.header()
+            .sleeping(self.side.peer())
+            .swap(false, Ordering::SeqCst)
+    }
+
+    /// Mark the channel closed. Returns true if the peer has to be woken up to notice
+    pub fn close(&self) -> bool {
+        self.header().closed.store(true, Ordering::SeqCst);
+        self.notify_peer()
+    }
+}
+
+#[repr(C, align(4096))]
+pub struct Slots<T, const CAP: usize>([UnsafeCell<T>; CAP]);
+
+impl<T: Copy, const CAP: usize> Slots<T, CAP> {
+    #[inline(always)]
+    fn push(&self, idx: &RingIndices, val: T) -> Result<(), T> {
+        let tail = idx.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(idx.head.load(Ordering::Acquire)) as usize >= CAP {
+            return Err(val);
+        }
+
+        unsafe { self.0[tail as usize % CAP].get().write_volatile(val) };
+        idx.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn pop(&self, idx: &RingIndices) -> Option<T> {
+        let head = idx.head.load(Ordering::Relaxed);
+        if idx.tail.load(Ordering::Acquire) == head {
+            return None;
+        }
+
+        let val = unsafe { self.0[head as usize % CAP].get().read_volatile() };
+        idx.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(val)
+    }
+}
+
+#[repr(C)]
+pub struct Channel<Req, Resp, const CAP: usize> {
+    pub header: ChannelHeader,
+    req: Slots<Req, CAP>,
+    resp: Slots<Resp, CAP>,
+}
+
+impl<Req: Copy, Resp: Copy, const CAP: usize> Channel<Req, Resp, CAP> {
+    /// Ring indices are free-running u32s, so CAP has to divide 2^32
+    const CAP_VALID: () = assert!(CAP.is_power_of_two() && CAP <= (1 << 31));
+
+    pub const PAGES: usize = size_of::<Self>() / PAGE_SIZE;
+
+    fn fresh_header() -> ChannelHeader {
+        let _ = Self::CAP_VALID;
+        ChannelHeader {
+            magic: CHANNEL_MAGIC,
+            version: CHANNEL_VERSION,
+            capacity: CAP as u32,
+            req_size: size_of::<Req>() as u32,
+            resp_size: size_of::<Resp>() as u32,
+            req: RingIndices {
+                head: AtomicU32::new(0),
+                tail: AtomicU32::new(0),
+            },
+            resp: RingIndices {
+                head: AtomicU32::new(0),
+                tail: AtomicU32::new(0),
+            },
+            client_sleeping: AtomicBool::new(false),
+            server_sleeping: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Allocate zeroed frames for a channel, with the header already filled in.
+    /// Frames are physically contiguous, so the kernel can reach the channel with `from_phys`
+    pub fn alloc() -> Result<Arc<SharedFrames>, OutOfMemory> {
+        let frames = Frame::alloc_contiguous(Self::PAGES)?;
+
+        let header: PhysAddr = frames[0].ppn().into();
+        unsafe { (header.0 as *mut ChannelHeader).write(Self::fresh_header()) };
+        Ok(SharedFrames::new(frames))
+    }
+
+    /// View a channel through the kernel identity mapping
+    ///
+    /// # Safety
+    /// `ppn` must be the first frame of a channel of this type allocated by `alloc`
+    pub unsafe fn from_phys<'a>(ppn: PhysPageNum) -> &'a Self {
+        let addr: PhysAddr = ppn.into();
+        &*(addr.0 as *const Self)
+    }
+
+    /// Check that the mapped pages were set up for this exact channel type
+    #[inline(always)]
+    pub fn is_valid(&self) -> bool {
+        let hdr = &self.header;
+        hdr.magic == CHANNEL_MAGIC
+            && hdr.version == CHANNEL_VERSION
+            && hdr.capacity as usize == CAP
+            && hdr.req_size as usize == size_of::<Req>()
+            && hdr.resp_size as usize == size_of::<Resp>()
+    }
+
+    #[inline(always)]
+    pub fn is_closed(&self) -> bool {
+        self.header.closed.load(Ordering::Acquire)
+    }
+
+    /// Set the sleeping flag of `side`, then recheck `ready`. Returns true if the caller should go
+    /// on to wait, or false (with the flag cleared again) if `ready` came true in the meantime
+    #[inline(always)]
+    pub fn prepare_wait(&self, side: Side, ready: impl Fn(&Self) -> bool) -> bool {
+        let flag = self.header.sleeping(side);
+        flag.store(true, Ordering::SeqCst);
+        if ready(self) {
+            flag.store(false, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    /// Whether the other end of `side` is waiting for a doorbell
+    #[inline(always)]
+    pub fn peer_sleeping(&self, side: Side) -> bool {
+        self.header.sleeping(side.peer()).load(Ordering::SeqCst)
+    }
+
+    /// Client side: check if a `send` would succeed right now
+    #[inline(always)]
+    pub fn can_send(&self) -> bool {
+        let idx = &self.header.req;
+        (idx.tail.load(Ordering::Relaxed).wrapping_sub(idx.head.load(Ordering::Acquire)) as usize)
+            < CAP
+    }
+
+    /// Client side: check if a `poll` would return something
+    #[inline(always)]
+    pub fn has_response(&self) -> bool {
+        self.header.resp.tail.load(Ordering::Acquire) != self.header.resp.head.load(Ordering::Relaxed)
+    }
+
+    /// Client side: enqueue a request, giving it back if the ring is full
+    #[inline(always)]
+    pub fn send(&self, req: Req) -> Result<(), Req> {
+        self.req.push(&self.header.req, req)
+    }
+
+    /// Client side: dequeue a response, if any
+    #[inline(always)]
+    pub fn poll(&self) -> Option<Resp> {
+        self.resp.pop(&self.header.resp)
+    }
+
+    /// Service side: dequeue a request, if any
+    #[inline(always)]
+    pub fn recv(&self) -> Option<Req> {
+        self.req.pop(&self.header.req)
+    }
+
+    /// Service side: check if a `recv` would return something
+    #[inline(always)]
+    pub fn has_request(&self) -> bool {
+        self.header.req.tail.load(Ordering::Acquire) != self.header.req.head.load(Ordering::Relaxed)
+    }
+
+    /// Service side: check if a `reply` would succeed right now
+    #[inline(always)]
+    pub fn can_reply(&self) -> bool {
+        let idx = &self.header.resp;
+        (idx.tail.load(Ordering::Relaxed).wrapping_sub(idx.head.load(Ordering::Acquire)) as usize)
+            < CAP
+    }
+
+    /// Service side: enqueue a response, giving it back if the ring is full
+    #[inline(always)]
+    pub fn reply(&self, resp: Resp) -> Result<(), Resp> {
+        self.resp.push(&self.header.resp, resp)
+    }
+}
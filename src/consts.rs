@@ -1,5 +1,10 @@
 pub const KERNEL_HEAP_SIZE: usize = 0x80_0000;
+/// Per hart
 pub const KERNEL_STACK_SIZE: usize = 0x8_0000;
+/// At the very top of each kernel stack, above the trap frame. Holds the hart id
+pub const HART_ID_SLOT: usize = 16;
+/// Harts with higher ids are left stopped
+pub const MAX_HARTS: usize = 4;
 
 pub const PHYS_MEMORY_END: usize = 0x8800_0000;
 
@@ -10,3 +15,6 @@ pub const PROCESS_STACK_TOP: usize = 0x80000000;
 
 pub const VDSO_RESIDE: usize = 0x60000000;
 pub const VDSO_DATA: usize = 0x62000000;
+pub const CHANNEL_BASE: usize = 0x64000000;
+/// Pages lent by peers and objects mapped through handles
+pub const MAP_BASE: usize = 0x68000000;
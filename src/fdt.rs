@@ -0,0 +1,137 @@
+//! Just enough of a flattened device tree parser to look up properties by node path
+//!
+//! Has to work before paging and the heap are set up, so it reads the blob in place and never
+//! allocates.
+
+const MAGIC: u32 = 0xd00d_feed;
+
+const BEGIN_NODE: u32 = 1;
+const END_NODE: u32 = 2;
+const PROP: u32 = 3;
+const NOP: u32 = 4;
+
+pub struct Fdt {
+    base: usize,
+    structs: usize,
+    strings: usize,
+}
+
+impl Fdt {
+    /// The blob firmware left at `addr`, if there is one
+    pub unsafe fn from_addr(addr: usize) -> Option<Self> {
+        if addr == 0 || addr % 4 != 0 {
+            return None;
+        }
+        let fdt = Self {
+            base: addr,
+            structs: 0,
+            strings: 0,
+        };
+        if fdt.be32(0) != MAGIC {
+            return None;
+        }
+        Some(Self {
+            structs: fdt.be32(8) as usize,
+            strings: fdt.be32(12) as usize,
+            ..fdt
+        })
+    }
+
+    fn be32(&self, offset: usize) -> u32 {
+        u32::from_be(unsafe { ((self.base + offset) as *const u32).read() })
+    }
+
+    /// Nul-terminated string at `offset`, without the nul
+    fn str(&self, offset: usize) -> &[u8] {
+        let start = (self.base + offset) as *const u8;
+        let mut len = 0;
+        while unsafe { *start.add(len) } != 0 {
+            len += 1;
+        }
+        unsafe { core::slice::from_raw_parts(start, len) }
+    }
+
+    /// Value of the property `name` of the node at `path`, like `/cpus`. Node names have to match
+    /// exactly, unit address included
+    pub fn property(&self, path: &str, name: &str) -> Option<&[u8]> {
+        let component = |level: usize| path.split('/').filter(|c| !c.is_empty()).nth(level);
+        let levels = path.split('/').filter(|c| !c.is_empty()).count();
+
+        // Nodes entered, and how many of them are on `path`
+        let mut depth: usize = 0;
+        let mut matched = 0;
+        let mut offset = self.structs;
+        loop {
+            let token = self.be32(offset);
+            offset += 4;
+            match token {
+                BEGIN_NODE => {
+                    let node = self.str(offset);
+                    offset += align4(node.len() + 1);
+                    // The root is the only node on level 0
+                    let on_path = component(depth.wrapping_sub(1)).map(str::as_bytes) == Some(node);
+                    if depth > 0 && matched == depth - 1 && on_path {
+                        matched = depth;
+                    }
+                    depth += 1;
+                }
+                END_NODE => {
+                    depth -= 1;
+                    matched = matched.min(depth.saturating_sub(1));
+                }
+                PROP => {
+                    let len = self.be32(offset) as usize;
+                    let name_offset = self.be32(offset + 4) as usize;
+                    offset += 8;
+                    let in_node = matched == levels && depth == levels + 1;
+                    if in_node && self.str(self.strings + name_offset) == name.as_bytes() {
+                        let value = (self.base + offset) as *const u8;
+                        return Some(unsafe { core::slice::from_raw_parts(value, len) });
+                    }
+                    offset += align4(len);
+                }
+                NOP => {}
+                // END, or something we don't know how to skip
+                _ => return None,
+            }
+        }
+    }
+}
+
+impl Fdt {
+    /// Property of the node of `hart` under `/cpus`, named `cpu@<hart>` by convention
+    pub fn cpu_property(&self, hart: usize, name: &str) -> Option<&[u8]> {
+        // Single digit unit addresses are all `MAX_HARTS` needs
+        let mut path = *b"/cpus/cpu@0";
+        path[10] = *b"0123456789abcdef".get(hart)?;
+        self.property(core::str::from_utf8(&path).unwrap(), name)
+    }
+
+    /// Whether `hart` has the ISA extension `ext`, going by either `riscv,isa-extensions` or the
+    /// older `riscv,isa` string
+    pub fn has_extension(&self, hart: usize, ext: &str) -> bool {
+        let ext = ext.as_bytes();
+        if let Some(list) = self.cpu_property(hart, "riscv,isa-extensions") {
+            return list.split(|&c| c == 0).any(|name| name == ext);
+        }
+        match self.cpu_property(hart, "riscv,isa") {
+            // The base ISA and single-letter extensions come first, multi-letter ones follow after
+            // underscores
+            Some(isa) => isa.split(|&c| c == b'_' || c == 0).skip(1).any(|name| name == ext),
+            None => false,
+        }
+    }
+}
+
+fn align4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Big-endian cell or pair of cells, as most numeric properties are stored
+pub fn read_cells(value: &[u8]) -> Option<usize> {
+    match value.len() {
+        4 => Some(u32::from_be_bytes(value.try_into().unwrap()) as usize),
+        8 => Some(u64::from_be_bytes(value.try_into().unwrap()) as usize),
+        _ => None,
+    }
+}
@@ -0,0 +1,94 @@
+//! Per-hart bring-up and state
+//!
+//! Every hart has its own kernel stack, with its trap frame right below the top. Above that sits
+//! its id, which trap entry loads into `tp`: user code is free to use `tp`, so it can't be trusted
+//! to hold the id on its own.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::consts::{HART_ID_SLOT, KERNEL_STACK_SIZE, MAX_HARTS};
+use crate::trap::TrapFrame;
+use crate::{mprintln, sbi};
+
+/// Bit mask of the harts that are up
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// Id of the current hart. Only valid in the kernel
+#[inline(always)]
+pub fn id() -> usize {
+    let id: usize;
+    unsafe {
+        core::arch::asm!("mv {}, tp", out(reg) id);
+    }
+    id
+}
+
+/// What `sscratch` holds while the hart runs a process: the end of its trap frame
+pub fn stack_top(id: usize) -> usize {
+    unsafe { crate::INIT_STACK.as_ptr() as usize + (id + 1) * KERNEL_STACK_SIZE - HART_ID_SLOT }
+}
+
+pub fn set_online() {
+    ONLINE.fetch_or(1 << id(), Ordering::SeqCst);
+}
+
+pub fn is_online(id: usize) -> bool {
+    id < MAX_HARTS && ONLINE.load(Ordering::SeqCst) & (1 << id) != 0
+}
+
+/// Start every other hart through SBI HSM. They come up in `secondary_entry`
+pub fn start_secondaries() {
+    for hartid in (0..MAX_HARTS).filter(|&hartid| hartid != id()) {
+        match sbi::hart_start(hartid, secondary_entry as usize, 0) {
+            Ok(()) => mprintln!("[Hart] starting {}", hartid),
+            // Not present
+            Err(e) => mprintln!("[Hart] failed to start {}: {:#x}", hartid, e),
+        }
+    }
+}
+
+/// Flush stale translations on every hart after unmapping pages from a process that might be
+/// running elsewhere
+pub fn shootdown() {
+    unsafe {
+        riscv::asm::sfence_vma_all();
+    }
+    let others = ONLINE.load(Ordering::SeqCst) & !(1 << id());
+    if others != 0 {
+        sbi::remote_sfence_vma(others);
+    }
+}
+
+/// Interrupt another hart, so it reschedules
+pub fn kick(id: usize) {
+    sbi::send_ipi(1 << id);
+}
+
+/// Set up the stack and `tp` of the hart in a0, then jump to `boot_secondary`. Harts without a
+/// stack are parked
+#[naked]
+unsafe extern "C" fn secondary_entry() -> ! {
+    core::arch::asm!(
+        "li t0, {max_harts}",
+        "bgeu a0, t0, 2f",
+        "mv tp, a0",
+        "la sp, INIT_STACK",
+        "li t0, {size}",
+        "addi t1, a0, 1",
+        "mul t1, t1, t0",
+        "add sp, sp, t1",
+        "addi sp, sp, -{reserve}",
+        "sd a0, {tf}(sp)",
+        "j boot_secondary",
+
+        "2:",
+        "wfi",
+        "j 2b",
+
+        size = const KERNEL_STACK_SIZE,
+        reserve = const HART_ID_SLOT + core::mem::size_of::<TrapFrame>(),
+        tf = const core::mem::size_of::<TrapFrame>(),
+        max_harts = const MAX_HARTS,
+        options(noreturn),
+    )
+}
@@ -5,8 +5,11 @@
 extern crate alloc;
 
 mod boot;
+mod channel;
 mod consts;
 mod elf;
+mod fdt;
+mod hart;
 mod lang_items;
 mod mem;
 mod process;
@@ -18,10 +21,13 @@ mod timer;
 mod trap;
 mod prog;
 mod service;
+mod syscall;
 
+/// Kernel stacks of all harts, one after the other
 #[link_section = ".data"]
 #[no_mangle]
-pub static mut INIT_STACK: [u8; consts::KERNEL_STACK_SIZE] = [0; consts::KERNEL_STACK_SIZE];
+pub static mut INIT_STACK: [u8; consts::KERNEL_STACK_SIZE * consts::MAX_HARTS] =
+    [0; consts::KERNEL_STACK_SIZE * consts::MAX_HARTS];
 
 extern "C" {
     fn _fw_start();
@@ -35,13 +41,36 @@ fn boot(hartid: usize, fdt_addr: usize) {
     serial::early_serial_init();
     serial::sbi_print("Early print initialized\n");
 
+    assert!(hartid < consts::MAX_HARTS, "Booting on hart {}", hartid);
+    // The device tree sits in memory the frame allocator takes over
+    timer::init_from_fdt(fdt_addr);
     trap::init();
     mem::init();
     timer::init();
+    service::init();
 
-    let init = process::Process::new_user(prog::TEST, [0, 0], Default::default());
-    sched::push(init);
+    let caps = process::UserCaps {
+        services: &["console"],
+        ..Default::default()
+    };
+    let init = process::Process::new_user(prog::TEST, [0, 0], caps).expect("No memory for init");
+    sched::push(init, 0);
+
+    hart::set_online();
+    hart::start_secondaries();
 
     // Make sure nothing is on stack
     sched::bootstrap();
+}
+
+/// Everything is set up by the boot hart already, only hart-local state is left
+#[no_mangle]
+fn boot_secondary(hartid: usize) {
+    mprintln!("[Hart] {} up", hartid);
+    trap::init();
+    mem::init_hart();
+    timer::init();
+
+    hart::set_online();
+    sched::bootstrap();
 }
\ No newline at end of file
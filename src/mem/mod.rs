@@ -1,7 +1,10 @@
-use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 use buddy_system_allocator::LockedHeap;
 
 use crate::consts::*;
+use crate::{mprintln, uprintln};
 use riscv::register::sstatus;
 
 use self::{addr::PhysPageNum, set::MemorySet};
@@ -14,11 +17,16 @@ pub mod set;
 static DYNAMIC_ALLOCATOR: LockedHeap<32> = LockedHeap::<32>::empty();
 
 pub fn init() {
+    init_hart();
+    init_heap();
+    init_frame();
+}
+
+/// What every hart needs for itself
+pub fn init_hart() {
     unsafe {
         sstatus::set_sum();
     }
-    init_heap();
-    init_frame();
 }
 
 fn init_heap() {
@@ -40,52 +48,134 @@ extern "C" {
     fn _frames_start();
 }
 
-// TODO: OOM
-enum NaiveFrameAllocator {
-    Init {
-        freelist: VecDeque<usize>,
-        ptr: usize,
-    },
-    Uninit,
+/// No free frames left
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct OutOfMemory;
+
+/// One bit per frame from `_frames_start` up to `PHYS_MEMORY_END`, set while it is allocated
+struct FrameAllocator {
+    /// First frame covered
+    base: usize,
+    frames: usize,
+    bitmap: Vec<u64>,
+    free: usize,
+    /// Where to start looking, everything before is likely taken
+    hint: usize,
 }
 
-impl NaiveFrameAllocator {
-    fn init(&mut self) {
-        *self = Self::Init {
-            freelist: VecDeque::new(),
-            ptr: addr::PhysAddr::from(_frames_start as usize).ceil().into(),
+impl FrameAllocator {
+    fn new() -> Self {
+        let base: usize = addr::PhysAddr::from(_frames_start as usize).ceil().into();
+        let end: usize = addr::PhysAddr::from(PHYS_MEMORY_END).floor().into();
+        let frames = end.saturating_sub(base);
+        Self {
+            base,
+            frames,
+            bitmap: vec![0; (frames + 63) / 64],
+            free: frames,
+            hint: 0,
+        }
+    }
+
+    fn is_free(&self, idx: usize) -> bool {
+        self.bitmap[idx / 64] & (1 << (idx % 64)) == 0
+    }
+
+    fn set(&mut self, idx: usize, used: bool) {
+        if used {
+            self.bitmap[idx / 64] |= 1 << (idx % 64);
+        } else {
+            self.bitmap[idx / 64] &= !(1 << (idx % 64));
+        }
+    }
+
+    /// First fit for `count` consecutive free frames, starting from the hint and wrapping around
+    fn find(&self, count: usize) -> Option<usize> {
+        let mut run = 0;
+        for idx in (self.hint..self.frames).chain(0..self.hint) {
+            // Runs don't wrap around the end
+            if idx == 0 {
+                run = 0;
+            }
+            if !self.is_free(idx) {
+                run = 0;
+                continue;
+            }
+            run += 1;
+            if run == count {
+                return Some(idx + 1 - count);
+            }
+        }
+        None
+    }
+
+    /// Returns the first frame of `count` consecutive ones
+    fn alloc(&mut self, count: usize) -> Result<usize, OutOfMemory> {
+        let start = match self.find(count) {
+            Some(start) if count <= self.free => start,
+            _ => {
+                uprintln!("[Mem] Out of memory allocating {} frames, {} free", count, self.free);
+                return Err(OutOfMemory);
+            }
+        };
+
+        for idx in start..start + count {
+            self.set(idx, true);
         }
+        self.free -= count;
+        self.hint = (start + count) % self.frames;
+        Ok(self.base + start)
+    }
+
+    fn dealloc(&mut self, ppn: usize) {
+        let idx = ppn - self.base;
+        assert!(idx < self.frames && !self.is_free(idx), "Freeing frame {:#x} twice", ppn);
+        self.set(idx, false);
+        self.free += 1;
+        self.hint = self.hint.min(idx);
     }
 }
 
-static FRAME_ALLOC: spin::Mutex<NaiveFrameAllocator> =
-    spin::Mutex::new(NaiveFrameAllocator::Uninit);
+static FRAME_ALLOC: spin::Mutex<Option<FrameAllocator>> = spin::Mutex::new(None);
 
 fn init_frame() {
-    let mut lock = FRAME_ALLOC.lock();
-    lock.init();
+    let allocator = FrameAllocator::new();
+    mprintln!("[Mem] {} frames from {:#x}", allocator.frames, allocator.base);
+    *FRAME_ALLOC.lock() = Some(allocator);
+}
+
+pub fn dump() {
+    let lock = FRAME_ALLOC.lock();
+    let allocator = lock.as_ref().expect("Frame allocator used before init");
+    uprintln!("[Mem] {} of {} frames free", allocator.free, allocator.frames);
 }
 
 pub struct Frame(usize);
 
-// TODO: OOM
 impl Frame {
-    pub fn alloc() -> Self {
-        match *FRAME_ALLOC.lock() {
-            NaiveFrameAllocator::Uninit => panic!("Frame allocated before init"),
-            NaiveFrameAllocator::Init {
-                ref mut freelist,
-                ref mut ptr,
-            } => {
-                if let Some(p) = freelist.pop_front() {
-                    return Self(p);
-                }
-
-                let alloc = *ptr;
-                *ptr += 1;
-                return Self(alloc);
-            }
-        }
+    /// Zeroed, since freed frames get handed out again
+    pub fn alloc() -> Result<Self, OutOfMemory> {
+        let mut lock = FRAME_ALLOC.lock();
+        let allocator = lock.as_mut().expect("Frame allocated before init");
+        let frame = Self(allocator.alloc(1)?);
+        drop(lock);
+        frame.zero();
+        Ok(frame)
+    }
+
+    /// Allocate physically contiguous frames, zeroed as well
+    pub fn alloc_contiguous(count: usize) -> Result<Vec<Self>, OutOfMemory> {
+        let mut lock = FRAME_ALLOC.lock();
+        let allocator = lock.as_mut().expect("Frame allocated before init");
+        let start = allocator.alloc(count)?;
+        drop(lock);
+        let frames: Vec<Self> = (start..start + count).map(Self).collect();
+        frames.iter().for_each(Self::zero);
+        Ok(frames)
+    }
+
+    fn zero(&self) {
+        unsafe { self.ppn().bytes_array().fill(0) };
     }
 
     pub fn ppn(&self) -> PhysPageNum {
@@ -95,13 +185,25 @@ impl Frame {
 
 impl Drop for Frame {
     fn drop(&mut self) {
-        match *FRAME_ALLOC.lock() {
-            NaiveFrameAllocator::Uninit => panic!("Frame de-allocated before init"),
-            NaiveFrameAllocator::Init {
-                ref mut freelist, ..
-            } => {
-                freelist.push_front(self.0);
-            }
-        }
+        let mut lock = FRAME_ALLOC.lock();
+        let allocator = lock.as_mut().expect("Frame de-allocated before init");
+        allocator.dealloc(self.0);
+    }
+}
+
+/// Frames mapped in more than one place. Freed once the last mapping or holder is dropped
+pub struct SharedFrames(Vec<Frame>);
+
+impl SharedFrames {
+    pub fn new(frames: Vec<Frame>) -> Arc<Self> {
+        Arc::new(Self(frames))
+    }
+
+    pub fn ppn(&self, idx: usize) -> PhysPageNum {
+        self.0[idx].ppn()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
     }
 }
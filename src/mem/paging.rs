@@ -3,7 +3,7 @@ use alloc::vec::Vec;
 
 use crate::consts::PAGE_SIZE;
 
-use super::{addr::*, Frame};
+use super::{addr::*, Frame, OutOfMemory};
 
 bitflags::bitflags! {
     pub struct PTEFlags: u8 {
@@ -53,18 +53,25 @@ pub struct PageTable {
 }
 
 impl PageTable {
-    pub fn new() -> Self {
-        let frame = Frame::alloc();
+    pub fn new() -> Result<Self, OutOfMemory> {
+        let frame = Frame::alloc()?;
         let ppn = frame.ppn();
         let frames = vec![frame];
 
-        PageTable { ppn, frames }
+        Ok(PageTable { ppn, frames })
     }
 
-    pub fn map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) {
-        let pte = self.find_pte_create(vpn).unwrap();
+    /// Fails if there is no frame left for an intermediate table
+    pub fn map(
+        &mut self,
+        vpn: VirtPageNum,
+        ppn: PhysPageNum,
+        flags: PTEFlags,
+    ) -> Result<(), OutOfMemory> {
+        let pte = self.find_pte_create(vpn)?;
         assert!(!pte.is_valid(), "vpn {:?} is mapped before mapping", vpn);
         *pte = PTE::new(ppn, flags | PTEFlags::V);
+        Ok(())
     }
 
     pub fn unmap(&mut self, vpn: VirtPageNum) {
@@ -86,24 +93,19 @@ impl PageTable {
         })
     }
 
-    fn find_pte_create(&mut self, vpn: VirtPageNum) -> Option<&mut PTE> {
+    fn find_pte_create(&mut self, vpn: VirtPageNum) -> Result<&mut PTE, OutOfMemory> {
         let idxs = vpn.indexes();
         let mut ppn = self.ppn;
-        let mut result: Option<&mut PTE> = None;
-        for i in 0..3 {
+        for i in 0..2 {
             let pte = unsafe { ppn.pte_within(idxs[i]) };
-            if i == 2 {
-                result = Some(pte);
-                break;
-            }
             if !pte.is_valid() {
-                let frame = Frame::alloc();
+                let frame = Frame::alloc()?;
                 *pte = PTE::new(frame.ppn(), PTEFlags::V);
                 self.frames.push(frame);
             }
             ppn = pte.ppn();
         }
-        result
+        Ok(unsafe { ppn.pte_within(idxs[2]) })
     }
 
     fn find_pte(&self, vpn: VirtPageNum) -> Option<&mut PTE> {
@@ -1,14 +1,14 @@
 use core::ops::{Range, RangeBounds};
 
-use alloc::{collections::BTreeMap, vec::Vec};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
 use elf_rs::ElfFile;
 
-use crate::{consts::PHYS_MEMORY_END, process::UserCaps};
+use crate::consts::PHYS_MEMORY_END;
 
 use super::{
     addr::{PhysPageNum, VirtAddr, VirtPageNum},
     paging::{PTEFlags, PageTable},
-    Frame,
+    Frame, OutOfMemory, SharedFrames,
 };
 
 bitflags::bitflags! {
@@ -27,17 +27,17 @@ pub struct MapArea {
 }
 
 impl MapArea {
-    pub fn frames(vpns: Range<VirtPageNum>, perm: MapPermission) -> Self {
+    pub fn frames(vpns: Range<VirtPageNum>, perm: MapPermission) -> Result<Self, OutOfMemory> {
         let mut frames: BTreeMap<VirtPageNum, Frame> = BTreeMap::new();
         for vpn in vpns.clone() {
-            frames.insert(vpn, Frame::alloc());
+            frames.insert(vpn, Frame::alloc()?);
         }
 
-        Self {
+        Ok(Self {
             vpns,
             perm,
             target: MapTarget::Framed { frames },
-        }
+        })
     }
 
     pub fn linear(ppns: Range<PhysPageNum>, base: VirtPageNum, perm: MapPermission) -> Self {
@@ -55,6 +55,30 @@ impl MapArea {
             target: MapTarget::Remote { remote },
         }
     }
+
+    /// Map frames owned by someone else to consecutive virtual pages starting at base
+    pub fn remote(ppns: &[PhysPageNum], base: VirtPageNum, perm: MapPermission) -> Self {
+        let remote = ppns
+            .iter()
+            .enumerate()
+            .map(|(idx, &ppn)| (VirtPageNum(base.0 + idx), ppn))
+            .collect();
+
+        Self {
+            vpns: base..VirtPageNum(base.0 + ppns.len()),
+            perm,
+            target: MapTarget::Remote { remote },
+        }
+    }
+
+    /// Map shared frames to consecutive virtual pages starting at base
+    pub fn shared(frames: Arc<SharedFrames>, base: VirtPageNum, perm: MapPermission) -> Self {
+        Self {
+            vpns: base..VirtPageNum(base.0 + frames.len()),
+            perm,
+            target: MapTarget::Shared { frames },
+        }
+    }
 }
 
 pub enum MapTarget {
@@ -63,7 +87,10 @@ pub enum MapTarget {
         frames: BTreeMap<VirtPageNum, Frame>,
     },
     Remote {
-        remote: BTreeMap<VirtPageNum, PhysPageNum>, // TODO: Frame with RC?
+        remote: BTreeMap<VirtPageNum, PhysPageNum>,
+    },
+    Shared {
+        frames: Arc<SharedFrames>,
     },
 }
 
@@ -73,19 +100,33 @@ pub struct MemorySet {
 }
 
 impl MemorySet {
-    pub fn new_bare() -> Self {
-        Self {
-            table: PageTable::new(),
+    pub fn new_bare() -> Result<Self, OutOfMemory> {
+        Ok(Self {
+            table: PageTable::new()?,
             areas: Vec::new(),
-        }
+        })
     }
 
-    pub fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) {
-        map_area.map(&mut self.table);
+    /// Leaves the set as it was if the area can't be mapped
+    pub fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) -> Result<(), OutOfMemory> {
+        map_area.map(&mut self.table)?;
         if let Some(data) = data {
             map_area.copy_data(&mut self.table, data);
         }
         self.areas.push(map_area);
+        Ok(())
+    }
+
+    /// Unmap and drop the area starting at `start`
+    pub fn remove(&mut self, start: VirtPageNum) -> bool {
+        match self.areas.iter().position(|area| area.vpns.start == start) {
+            Some(idx) => {
+                let mut area = self.areas.remove(idx);
+                area.unmap(&mut self.table);
+                true
+            }
+            None => false,
+        }
     }
 
     /// Assume that no conflicts.
@@ -94,7 +135,7 @@ impl MemorySet {
         start_va: VirtAddr,
         end_va: VirtAddr,
         permission: MapPermission,
-    ) {
+    ) -> Result<(), OutOfMemory> {
         self.push(
             MapArea::new(
                 start_va,
@@ -105,11 +146,11 @@ impl MemorySet {
                 permission,
             ),
             None,
-        );
+        )
     }
 
-    pub fn new_kernel(caps: UserCaps) -> Self {
-        let mut memory_set = Self::new_bare();
+    pub fn new_kernel() -> Result<Self, OutOfMemory> {
+        let mut memory_set = Self::new_bare()?;
         // map trampoline
         // memory_set.map_trampoline();
 
@@ -148,7 +189,7 @@ impl MemorySet {
                 MapPermission::R | MapPermission::X,
             ),
             None,
-        );
+        )?;
         crate::mprintln!("mapping .rodata section");
         memory_set.push(
             MapArea::new(
@@ -158,7 +199,7 @@ impl MemorySet {
                 MapPermission::R,
             ),
             None,
-        );
+        )?;
         crate::mprintln!("mapping .bss + .data section");
         memory_set.push(
             MapArea::new(
@@ -168,7 +209,7 @@ impl MemorySet {
                 MapPermission::R | MapPermission::W,
             ),
             None,
-        );
+        )?;
         crate::mprintln!("mapping physical memory");
         memory_set.push(
             MapArea::new(
@@ -178,24 +219,95 @@ impl MemorySet {
                 MapPermission::R | MapPermission::W,
             ),
             None,
-        );
+        )?;
 
+        // User processes reach it through an MMIO handle instead
         crate::mprintln!("Mapping serial port");
-        let mut serial_perm = MapPermission::R | MapPermission::W;
-        if caps.serial {
-            serial_perm |= MapPermission::U;
-        }
         memory_set.push(
             MapArea::new(
                 0x10000000.into(),
                 0x10001000.into(),
                 MapTarget::Identical,
-                serial_perm,
+                MapPermission::R | MapPermission::W,
             ),
             None,
-        );
+        )?;
+
+        Ok(memory_set)
+    }
+
+    /// Copy bytes out of user memory. Fails if any page in the range is not user readable
+    pub fn read_user(&self, start: VirtAddr, len: usize) -> Option<Vec<u8>> {
+        let end = start.0.checked_add(len)?;
+        let mut result = Vec::with_capacity(len);
+        let mut cur = start;
+        while cur.0 < end {
+            let pte = self.table.translate(cur.floor())?;
+            if !pte.is_valid() || !pte.flags().contains(PTEFlags::U | PTEFlags::R) {
+                return None;
+            }
 
-        memory_set
+            let chunk_end = VirtAddr::from(VirtPageNum(cur.floor().0 + 1)).0.min(end);
+            let offset = cur.page_offset();
+            let page = unsafe { pte.ppn().bytes_array() };
+            result.extend_from_slice(&page[offset..offset + (chunk_end - cur.0)]);
+            cur = VirtAddr(chunk_end);
+        }
+        Some(result)
+    }
+
+    /// Copy bytes into user memory. Fails without writing anything if any page in the range is not
+    /// user writable
+    pub fn write_user(&self, start: VirtAddr, data: &[u8]) -> bool {
+        let end = match start.0.checked_add(data.len()) {
+            Some(end) => end,
+            None => return false,
+        };
+        let mut pages = Vec::new();
+        let mut cur = start;
+        while cur.0 < end {
+            match self.table.translate(cur.floor()) {
+                Some(pte) if pte.is_valid() && pte.flags().contains(PTEFlags::U | PTEFlags::W) => {
+                    pages.push(pte.ppn())
+                }
+                _ => return false,
+            }
+            cur = VirtAddr::from(VirtPageNum(cur.floor().0 + 1));
+        }
+
+        let mut cur = start;
+        for ppn in pages {
+            let chunk_end = VirtAddr::from(VirtPageNum(cur.floor().0 + 1)).0.min(end);
+            let offset = cur.page_offset();
+            let len = chunk_end - cur.0;
+            let page = unsafe { ppn.bytes_array() };
+            let done = cur.0 - start.0;
+            page[offset..offset + len].copy_from_slice(&data[done..done + len]);
+            cur = VirtAddr(chunk_end);
+        }
+        true
+    }
+
+    /// Frames behind `count` pages starting at `start`. Fails unless every page belongs to a framed
+    /// area, i.e. memory the process owns rather than has been lent, with at least `perm`
+    pub fn owned_ppns(
+        &self,
+        start: VirtPageNum,
+        count: usize,
+        perm: MapPermission,
+    ) -> Option<Vec<PhysPageNum>> {
+        (start.0..start.0.checked_add(count)?)
+            .map(|vpn| {
+                let vpn = VirtPageNum(vpn);
+                let area = self.areas.iter().find(|area| area.vpns.contains(&vpn))?;
+                match area.target {
+                    MapTarget::Framed { ref frames } if area.perm.contains(perm) => {
+                        frames.get(&vpn).map(Frame::ppn)
+                    }
+                    _ => None,
+                }
+            })
+            .collect()
     }
 
     pub fn activate(&self) {
@@ -228,10 +340,17 @@ impl MapArea {
         }
     }
 
-    pub fn map(&mut self, page_table: &mut PageTable) {
+    /// Unmaps what it already mapped if it fails partway. Frames go away with the area
+    pub fn map(&mut self, page_table: &mut PageTable) -> Result<(), OutOfMemory> {
         for vpn in self.vpns.clone() {
-            self.map_one(page_table, vpn);
+            if let Err(err) = self.map_one(page_table, vpn) {
+                for mapped in self.vpns.start.0..vpn.0 {
+                    page_table.unmap(VirtPageNum(mapped));
+                }
+                return Err(err);
+            }
         }
+        Ok(())
     }
 
     pub fn unmap(&mut self, page_table: &mut PageTable) {
@@ -251,20 +370,25 @@ impl MapArea {
         }
     }
 
-    pub fn map_one(&mut self, table: &mut PageTable, vpn: VirtPageNum) {
+    pub fn map_one(&mut self, table: &mut PageTable, vpn: VirtPageNum) -> Result<(), OutOfMemory> {
         let ppn = match self.target {
             MapTarget::Identical => PhysPageNum(vpn.0),
-            MapTarget::Framed { ref mut frames } => {
-                let frame = Frame::alloc();
-                let ppn = frame.ppn();
-                frames.insert(vpn, frame);
-                ppn
-            }
+            // Areas from `MapArea::frames` come with theirs
+            MapTarget::Framed { ref mut frames } => match frames.get(&vpn) {
+                Some(frame) => frame.ppn(),
+                None => {
+                    let frame = Frame::alloc()?;
+                    let ppn = frame.ppn();
+                    frames.insert(vpn, frame);
+                    ppn
+                }
+            },
             MapTarget::Remote { ref remote } => remote.get(&vpn).unwrap().clone(),
+            MapTarget::Shared { ref frames } => frames.ppn(vpn.0 - self.vpns.start.0),
         };
 
         let pte_flags = PTEFlags::from_bits(self.perm.bits).unwrap();
-        table.map(vpn, ppn, pte_flags);
+        table.map(vpn, ppn, pte_flags)
     }
     pub fn unmap_one(&mut self, table: &mut PageTable, vpn: VirtPageNum) {
         match self.target {
@@ -275,6 +399,8 @@ impl MapArea {
             MapTarget::Remote { ref mut remote } => {
                 remote.remove(&vpn);
             }
+            // Frames go away with the last reference to them
+            MapTarget::Shared { .. } => {}
         }
         table.unmap(vpn);
     }
@@ -0,0 +1,164 @@
+//! Per-process handle tables
+//!
+//! Everything a process may act on beyond its own memory is reached through a handle: a small
+//! integer indexing its table, together with the rights it holds on the object. Handle 0 is never
+//! valid, so syscalls can use it to mean "none" or "all".
+
+use core::ops::Range;
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{channel::Endpoint, mem::addr::PhysPageNum, mem::SharedFrames, syscall::SysError};
+
+bitflags::bitflags! {
+    pub struct Rights: u32 {
+        /// Map the object readable
+        const READ = 1 << 0;
+        /// Map the object writable
+        const WRITE = 1 << 1;
+        /// Map the object at all
+        const MAP = 1 << 2;
+        /// Ring the doorbell of a channel, or lend pages through it
+        const SIGNAL = 1 << 3;
+        /// Wait on a channel
+        const WAIT = 1 << 4;
+        /// Create another handle to the same object, with at most the same rights
+        const DUPLICATE = 1 << 5;
+        /// Move the handle to the peer of a channel
+        const TRANSFER = 1 << 6;
+    }
+}
+
+impl Rights {
+    /// Channels can't be duplicated: an endpoint belongs to the process it is mapped into, and
+    /// closing a copy would unmap the channel under the other handle. Moving one remaps it into the
+    /// new owner and points the other end at it
+    pub const CHANNEL: Self = Self::from_bits_truncate(
+        Self::READ.bits | Self::WRITE.bits | Self::SIGNAL.bits | Self::WAIT.bits | Self::TRANSFER.bits,
+    );
+    pub const MEMORY: Self = Self::from_bits_truncate(
+        Self::READ.bits | Self::WRITE.bits | Self::MAP.bits | Self::DUPLICATE.bits | Self::TRANSFER.bits,
+    );
+    pub const MMIO: Self = Self::MEMORY;
+    pub const PROCESS: Self =
+        Self::from_bits_truncate(Self::WAIT.bits | Self::DUPLICATE.bits | Self::TRANSFER.bits);
+}
+
+pub enum KObject {
+    Channel(Endpoint),
+    /// Anonymous memory, freed once the last handle and mapping are gone
+    Memory(Arc<SharedFrames>),
+    /// Refers to a process by pid. Pids are never reused
+    Process(usize),
+    /// Device registers
+    Mmio(Range<PhysPageNum>),
+}
+
+impl KObject {
+    /// A second reference to the same object, if the kind allows it
+    pub fn try_clone(&self) -> Option<Self> {
+        match self {
+            KObject::Channel(_) => None,
+            KObject::Memory(frames) => Some(KObject::Memory(frames.clone())),
+            KObject::Process(pid) => Some(KObject::Process(*pid)),
+            KObject::Mmio(ppns) => Some(KObject::Mmio(ppns.clone())),
+        }
+    }
+}
+
+pub struct Handle {
+    pub object: KObject,
+    pub rights: Rights,
+}
+
+#[derive(Default)]
+pub struct HandleTable {
+    slots: Vec<Option<Handle>>,
+}
+
+impl HandleTable {
+    /// Store a handle in the first free slot, returning its number
+    pub fn insert(&mut self, object: KObject, rights: Rights) -> usize {
+        let handle = Some(Handle { object, rights });
+        match self.slots.iter().position(Option::is_none) {
+            Some(idx) => {
+                self.slots[idx] = handle;
+                idx + 1
+            }
+            None => {
+                self.slots.push(handle);
+                self.slots.len()
+            }
+        }
+    }
+
+    /// Look up a handle, checking that it carries all of `rights`
+    pub fn get(&self, handle: usize, rights: Rights) -> Result<&Handle, SysError> {
+        let entry = handle
+            .checked_sub(1)
+            .and_then(|idx| self.slots.get(idx))
+            .and_then(Option::as_ref)
+            .ok_or(SysError::InvalidArgument)?;
+
+        if !entry.rights.contains(rights) {
+            return Err(SysError::Denied);
+        }
+        Ok(entry)
+    }
+
+    pub fn remove(&mut self, handle: usize) -> Option<Handle> {
+        self.slots.get_mut(handle.checked_sub(1)?)?.take()
+    }
+
+    /// Create a new handle to the same object with `rights`, which may only drop some
+    pub fn duplicate(&mut self, handle: usize, rights: Rights) -> Result<usize, SysError> {
+        let entry = self.get(handle, Rights::DUPLICATE)?;
+        if !entry.rights.contains(rights) {
+            return Err(SysError::Denied);
+        }
+
+        let object = entry.object.try_clone().ok_or(SysError::InvalidArgument)?;
+        Ok(self.insert(object, rights))
+    }
+
+    pub fn channel(&self, handle: usize, rights: Rights) -> Result<&Endpoint, SysError> {
+        match self.get(handle, rights)?.object {
+            KObject::Channel(ref ep) => Ok(ep),
+            _ => Err(SysError::InvalidArgument),
+        }
+    }
+
+    /// Handles and endpoints of every channel held
+    pub fn channels(&self) -> impl Iterator<Item = (usize, &Endpoint)> {
+        self.slots.iter().enumerate().filter_map(|(idx, slot)| match slot {
+            Some(Handle {
+                object: KObject::Channel(ep),
+                ..
+            }) => Some((idx + 1, ep)),
+            _ => None,
+        })
+    }
+
+    pub fn channels_mut(&mut self) -> impl Iterator<Item = &mut Endpoint> {
+        self.slots.iter_mut().filter_map(|slot| match slot {
+            Some(Handle {
+                object: KObject::Channel(ep),
+                ..
+            }) => Some(ep),
+            _ => None,
+        })
+    }
+
+    /// Whether any handle refers to the process `pid`
+    pub fn refers_to(&self, pid: usize) -> bool {
+        self.slots
+            .iter()
+            .flatten()
+            .any(|handle| matches!(handle.object, KObject::Process(held) if held == pid))
+    }
+
+    /// Empty the table, e.g. when the process exits
+    pub fn drain(&mut self) -> impl Iterator<Item = Handle> + '_ {
+        self.slots.drain(..).flatten()
+    }
+}
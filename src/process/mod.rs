@@ -1,45 +1,164 @@
+use alloc::{sync::Arc, vec::Vec};
 use elf_rs::{ElfFile, SectionHeaderFlags, SectionType};
 
+pub mod handle;
+mod region;
+
+use handle::{HandleTable, KObject, Rights};
+use region::Region;
+
 use crate::{
-    consts::{PROCESS_STACK_TOP, VDSO_DATA, VDSO_RESIDE},
+    channel::{Endpoint, Side},
+    consts::{CHANNEL_BASE, MAP_BASE, PAGE_SIZE, PROCESS_STACK_TOP, VDSO_DATA, VDSO_RESIDE},
     elf::Dynamic,
     mem::{
-        addr::{PhysAddr, VirtAddr, VirtPageNum},
+        addr::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum},
         set::{MapArea, MapPermission, MemorySet},
+        OutOfMemory, SharedFrames,
     },
     mprintln,
+    provided::channel_doorbell,
+    provided::channel_grant,
+    provided::channel_lent,
+    provided::channel_revoke,
+    provided::channel_wait,
+    provided::console_write,
+    provided::exit,
+    provided::handle_close,
+    provided::handle_dup,
+    provided::handle_map,
+    provided::handle_transfer,
+    provided::handle_unmap,
     provided::kernel_meow,
+    provided::mem_create,
+    provided::proc_dump,
+    provided::proc_info,
+    provided::process_wait,
     provided::putchar_async,
     provided::putchar_sync,
-    trap::TrapFrame,
+    provided::putchar_wait,
+    provided::sleep_for,
+    provided::sleep_until,
+    provided::timebase,
+    provided::VdsoData,
+    provided::channel_wait_until,
+    provided::sched_pin_hart,
+    provided::sched_reserve,
+    provided::set_priority,
+    provided::yield_now,
+    sched::{stats::ProcStats, KernelRequest, ProcState, WaitKey, DEFAULT_PRIORITY},
+    syscall::SysError,
+    timer::{self, TimerId},
+    trap::{kernel_exit, TrapFrame},
 };
 
 pub struct Process {
     pub mset: MemorySet,
     pub tf: TrapFrame,
+    pub user: bool,
+    /// Managed by the scheduler
+    pub state: ProcState,
+    /// Queues the process sits on while blocked
+    pub waits: Vec<WaitKey>,
+    /// Wakes it up if it is still blocked by then
+    pub timeout: Option<TimerId>,
+    pub stats: ProcStats,
+    /// Base priority level, lower runs first. How it is used is up to the scheduling policy
+    pub priority: usize,
+    pub caps: UserCaps,
+    pub handles: HandleTable,
+    /// Free slots of the channel mapping region
+    channels: Region,
+    /// Free slots of the region for grants and mapped objects
+    maps: Region,
+    /// Where objects have been mapped through handles, and how many pages
+    mapped: Vec<(usize, usize)>,
+    /// Pages of this process currently lent to peers
+    pub grants: Vec<Grant>,
+    /// Left for the scheduler by a kernel process, which can't make syscalls
+    pub kernel_request: Option<KernelRequest>,
+    /// Pid of the process that spawned it, 0 for the kernel. Orphans are handed to the kernel
+    pub parent: usize,
+}
+
+/// Pages lent to a peer. They stay mapped there until revoked, at the latest when the owner exits
+pub struct Grant {
+    pub peer: usize,
+    /// First frame of the channel it was lent through
+    pub channel: usize,
+    pub perm: MapPermission,
+    /// Where the pages are mapped in the peer
+    pub peer_base: usize,
+    pub pages: usize,
 }
 
 lazy_static::lazy_static! {
-    static ref EXPORTED_METHODS: [(&'static [u8], usize); 2] = [
+    static ref EXPORTED_METHODS: [(&'static [u8], usize); 27] = [
         (b"kernel_meow", kernel_meow as usize),
         // (b"putchar", putchar_sync as usize),
         (b"putchar", putchar_async as usize),
+        (b"putchar_wait", putchar_wait as usize),
+        (b"console_write", console_write as usize),
+        (b"channel_wait", channel_wait as usize),
+        (b"channel_doorbell", channel_doorbell as usize),
+        (b"channel_grant", channel_grant as usize),
+        (b"channel_revoke", channel_revoke as usize),
+        (b"channel_lent", channel_lent as usize),
+        (b"handle_close", handle_close as usize),
+        (b"handle_dup", handle_dup as usize),
+        (b"handle_transfer", handle_transfer as usize),
+        (b"handle_map", handle_map as usize),
+        (b"handle_unmap", handle_unmap as usize),
+        (b"mem_create", mem_create as usize),
+        (b"exit", exit as usize),
+        (b"process_wait", process_wait as usize),
+        (b"yield_now", yield_now as usize),
+        (b"set_priority", set_priority as usize),
+        (b"sched_reserve", sched_reserve as usize),
+        (b"sched_pin_hart", sched_pin_hart as usize),
+        (b"sleep_until", sleep_until as usize),
+        (b"sleep_for", sleep_for as usize),
+        (b"channel_wait_until", channel_wait_until as usize),
+        (b"proc_info", proc_info as usize),
+        (b"proc_dump", proc_dump as usize),
+        (b"timebase", timebase as usize),
     ];
 }
 
+/// Restrictions fixed at spawn time. Everything else a process may touch is held as a handle
 #[derive(Default, Clone, Copy)]
 pub struct UserCaps {
-    pub serial: bool,
+    /// Names of the services the process may connect to
+    pub services: &'static [&'static str],
+    /// Whether the process may change priorities, its own or those of processes it has handles to
+    pub set_priority: bool,
+    /// Whether the process may reserve CPU time as a deadline process
+    pub reserve: bool,
+    /// Whether the process may have a hart to itself
+    pub pin_hart: bool,
+    /// Whether the process may read scheduling counters and dump kernel state to the console
+    pub stats: bool,
+}
+
+impl UserCaps {
+    pub fn may_connect(&self, service: &str) -> bool {
+        self.services.contains(&service)
+    }
 }
 
 impl Process {
-    pub fn new_user(elf: &[u8], data: [usize; 2], caps: UserCaps) -> Process {
+    /// Fails if physical memory runs out, with everything allocated so far freed
+    pub fn new_user(
+        elf: &[u8],
+        data: [usize; 2],
+        caps: UserCaps,
+    ) -> Result<Process, OutOfMemory> {
         let parsed = elf_rs::Elf64::from_bytes(elf).unwrap();
         let header = parsed.elf_header();
 
         // crate::mprintln!("{:?}", header);
 
-        let mut mset = MemorySet::new_kernel(caps);
+        let mut mset = MemorySet::new_kernel()?;
 
         let mut dynamic = None;
 
@@ -81,8 +200,8 @@ impl Process {
             }
             // mprintln!("Perm: {:?}", perm);
 
-            let area = MapArea::frames(virt_start.into()..virt_end.into(), perm);
-            mset.push(area, src);
+            let area = MapArea::frames(virt_start.into()..virt_end.into(), perm)?;
+            mset.push(area, src)?;
         }
 
         // Map VDSO text
@@ -98,15 +217,25 @@ impl Process {
             text_vdso_start_vpn,
             MapPermission::U | MapPermission::R | MapPermission::X,
         );
-        mset.push(text_vdso_area, None);
+        mset.push(text_vdso_area, None)?;
 
         // Map vdso data
         let data_vdso_start_vpn = VirtAddr(VDSO_DATA).floor();
         let data_vdso_frames = MapArea::frames(
             data_vdso_start_vpn..VirtPageNum(data_vdso_start_vpn.0 + 1),
             MapPermission::U | MapPermission::R | MapPermission::W,
-        );
-        mset.push(data_vdso_frames, Some(&[0u8; 0x1000]));
+        )?;
+        let mut vdso_data = [0u8; PAGE_SIZE];
+        unsafe {
+            (vdso_data.as_mut_ptr() as *mut VdsoData).write_unaligned(VdsoData {
+                console: 0,
+                console_handle: 0,
+                console_process: 0,
+                next_user_data: 0,
+                timebase: timer::timebase(),
+            });
+        }
+        mset.push(data_vdso_frames, Some(&vdso_data))?;
 
         if let Some(dynamic) = &dynamic {
             if let Some(inner) = &dynamic.rel {
@@ -175,39 +304,210 @@ impl Process {
         let stack_area = MapArea::frames(
             stack_start..stack_end,
             MapPermission::U | MapPermission::W | MapPermission::R,
-        );
-        mset.push(stack_area, None);
+        )?;
+        mset.push(stack_area, None)?;
 
         let entry = parsed.entry_point() as usize;
         mprintln!("Entry: {:#x}", entry);
         let mut tf = TrapFrame::with_process(true, entry, PROCESS_STACK_TOP);
+        // Returning from the entry point exits
+        tf.x[1] = VDSO_RESIDE + (exit as usize - _text_vdso_start as usize);
         tf.x[10] = data[0];
         tf.x[11] = data[1];
 
-        let process = Process { tf, mset };
+        let process = Process {
+            tf,
+            mset,
+            user: true,
+            state: ProcState::Ready,
+            waits: Vec::new(),
+            timeout: None,
+            stats: Default::default(),
+            priority: DEFAULT_PRIORITY,
+            caps,
+            handles: Default::default(),
+            channels: Region::new(CHANNEL_BASE..MAP_BASE),
+            maps: Region::new(MAP_BASE..PROCESS_STACK_TOP - 16 * PAGE_SIZE),
+            mapped: Vec::new(),
+            grants: Vec::new(),
+            kernel_request: None,
+            parent: 0,
+        };
 
-        process
+        Ok(process)
     }
 
-    pub fn new_kernel(entry: usize, data: [usize; 2]) -> Process {
-        let mut mset = MemorySet::new_kernel(Default::default());
+    pub fn new_kernel(entry: usize, data: [usize; 2]) -> Result<Process, OutOfMemory> {
+        let mut mset = MemorySet::new_kernel()?;
         // Allocate stack
 
         // TODO: extendable stack
         let stack_end = VirtAddr(PROCESS_STACK_TOP).ceil();
         let stack_start = VirtPageNum(stack_end.0 - 16usize);
         let stack_area =
-            MapArea::frames(stack_start..stack_end, MapPermission::W | MapPermission::R);
-        mset.push(stack_area, None);
+            MapArea::frames(stack_start..stack_end, MapPermission::W | MapPermission::R)?;
+        mset.push(stack_area, None)?;
 
         let entry = entry as usize;
         mprintln!("Entry: {:#x}", entry);
         let mut tf = TrapFrame::with_process(false, entry, PROCESS_STACK_TOP);
+        tf.x[1] = kernel_exit as usize;
         tf.x[10] = data[0];
         tf.x[11] = data[1];
 
-        let process = Process { tf, mset };
+        let process = Process {
+            tf,
+            mset,
+            user: false,
+            state: ProcState::Ready,
+            waits: Vec::new(),
+            timeout: None,
+            stats: Default::default(),
+            priority: DEFAULT_PRIORITY,
+            caps: Default::default(),
+            handles: Default::default(),
+            channels: Region::new(CHANNEL_BASE..MAP_BASE),
+            maps: Region::new(MAP_BASE..PROCESS_STACK_TOP - 16 * PAGE_SIZE),
+            mapped: Vec::new(),
+            grants: Vec::new(),
+            kernel_request: None,
+            parent: 0,
+        };
+
+        Ok(process)
+    }
+
+    /// Map channel pages at the next free slot of the channel region.
+    /// Returns the handle to the new endpoint and the base address
+    pub fn map_channel(
+        &mut self,
+        frames: &Arc<SharedFrames>,
+        side: Side,
+        peer: usize,
+        rights: Rights,
+    ) -> Result<(usize, usize), SysError> {
+        let len = frames.len() * PAGE_SIZE;
+        let base = self.channels.alloc(len).ok_or(SysError::Busy)?;
+
+        let mut perm = MapPermission::R | MapPermission::W;
+        if self.user {
+            perm |= MapPermission::U;
+        }
+        let area = MapArea::shared(frames.clone(), VirtAddr(base).into(), perm);
+        if let Err(err) = self.mset.push(area, None) {
+            self.channels.free(base, len);
+            return Err(err.into());
+        }
+        let ep = Endpoint {
+            base,
+            frames: frames.clone(),
+            side,
+            peer,
+        };
+        Ok((self.handles.insert(KObject::Channel(ep), rights), base))
+    }
+
+    /// Drop a channel handle and unmap the channel, handing back its endpoint so the caller can
+    /// close it
+    pub fn unmap_channel(&mut self, handle: usize) -> Result<Endpoint, SysError> {
+        self.handles.channel(handle, Rights::empty())?;
+        let ep = match self.handles.remove(handle).unwrap().object {
+            KObject::Channel(ep) => ep,
+            _ => unreachable!(),
+        };
+
+        let unmapped = self.mset.remove(VirtAddr(ep.base).into());
+        assert!(unmapped, "Channel at {:#x} not mapped", ep.base);
+        self.channels.free(ep.base, ep.frames.len() * PAGE_SIZE);
+        Ok(ep)
+    }
+
+    /// Push the area `build` makes of `pages` pages at a free slot of the map region, or fail if
+    /// there is none large enough
+    fn push_mapped(
+        &mut self,
+        pages: usize,
+        build: impl FnOnce(VirtPageNum) -> MapArea,
+    ) -> Result<usize, SysError> {
+        let len = pages * PAGE_SIZE;
+        let base = self.maps.alloc(len).ok_or(SysError::Busy)?;
+        if let Err(err) = self.mset.push(build(VirtAddr(base).into()), None) {
+            self.maps.free(base, len);
+            return Err(err.into());
+        }
+        Ok(base)
+    }
+
+    /// Unmap what `push_mapped` mapped and free its slot
+    fn remove_mapped(&mut self, base: usize, pages: usize) -> bool {
+        let removed = self.mset.remove(VirtAddr(base).into());
+        if removed {
+            self.maps.free(base, pages * PAGE_SIZE);
+        }
+        removed
+    }
+
+    fn map_perm(&self, mut perm: MapPermission) -> MapPermission {
+        if self.user {
+            perm |= MapPermission::U;
+        }
+        perm
+    }
+
+    /// Map pages lent by a peer, returning the base address
+    pub fn map_grant(&mut self, ppns: &[PhysPageNum], perm: MapPermission) -> Result<usize, SysError> {
+        let perm = self.map_perm(perm);
+        self.push_mapped(ppns.len(), |base| MapArea::remote(ppns, base, perm))
+    }
+
+    /// Undo `map_grant`
+    pub fn unmap_grant(&mut self, base: usize, pages: usize) -> bool {
+        self.remove_mapped(base, pages)
+    }
+
+    /// Map a memory or MMIO object, readable and, with the right to, writable
+    pub fn map_object(&mut self, handle: usize) -> Result<usize, SysError> {
+        let entry = self.handles.get(handle, Rights::MAP | Rights::READ)?;
+        let mut perm = MapPermission::R;
+        if entry.rights.contains(Rights::WRITE) {
+            perm |= MapPermission::W;
+        }
+
+        let perm = self.map_perm(perm);
+        let (pages, addr) = match entry.object.try_clone() {
+            Some(KObject::Memory(frames)) => {
+                let pages = frames.len();
+                (pages, self.push_mapped(pages, move |base| MapArea::shared(frames, base, perm))?)
+            }
+            Some(KObject::Mmio(ppns)) => {
+                let pages = ppns.end.0 - ppns.start.0;
+                (pages, self.push_mapped(pages, move |base| MapArea::linear(ppns, base, perm))?)
+            }
+            _ => return Err(SysError::InvalidArgument),
+        };
+
+        self.mapped.push((addr, pages));
+        Ok(addr)
+    }
+
+    /// Undo `map_object`. Lent pages can only be taken back by their owner
+    pub fn unmap_object(&mut self, addr: usize) -> Result<(), SysError> {
+        let idx = self
+            .mapped
+            .iter()
+            .position(|&(base, _)| base == addr)
+            .ok_or(SysError::InvalidArgument)?;
+        let (_, pages) = self.mapped.remove(idx);
+        self.remove_mapped(addr, pages);
+        Ok(())
+    }
 
-        process
+    /// Whether a wait on `handle` (0 for all channels) should still block: every channel waited
+    /// on has its sleeping flag set, so no doorbell arrived in between
+    pub fn wait_armed(&self, handle: usize) -> bool {
+        self.handles
+            .channels()
+            .filter(|&(h, _)| handle == 0 || h == handle)
+            .all(|(_, ep)| ep.armed())
     }
 }
@@ -0,0 +1,44 @@
+//! Parts of a process's address space handed out piecewise, like the channel region
+
+use core::ops::Range;
+
+use alloc::{vec, vec::Vec};
+
+/// First fit over the free ranges. Released ranges are merged with their neighbours again
+pub struct Region {
+    /// Sorted, and never touching one another
+    free: Vec<Range<usize>>,
+}
+
+impl Region {
+    pub fn new(range: Range<usize>) -> Self {
+        Self { free: vec![range] }
+    }
+
+    /// Start of `len` free bytes, or nothing if no gap is large enough
+    pub fn alloc(&mut self, len: usize) -> Option<usize> {
+        let idx = self.free.iter().position(|range| range.end - range.start >= len)?;
+        let base = self.free[idx].start;
+        self.free[idx].start += len;
+        if self.free[idx].is_empty() {
+            self.free.remove(idx);
+        }
+        Some(base)
+    }
+
+    /// Give back what `alloc` handed out
+    pub fn free(&mut self, base: usize, len: usize) {
+        let end = base + len;
+        let idx = self.free.partition_point(|range| range.end <= base);
+        let joins_prev = idx > 0 && self.free[idx - 1].end == base;
+        let joins_next = idx < self.free.len() && self.free[idx].start == end;
+        match (joins_prev, joins_next) {
+            (true, true) => {
+                self.free[idx - 1].end = self.free.remove(idx).end;
+            }
+            (true, false) => self.free[idx - 1].end = end,
+            (false, true) => self.free[idx].start = base,
+            (false, false) => self.free.insert(idx, base..end),
+        }
+    }
+}
@@ -13,4 +13,4 @@ pub fn idle() -> ! {
 pub static TEST: &'static [u8] = include_aligned!(Align64, "../user/test.elf");
 
 #[link_section = ".rodata"]
-pub static PUTCHAR: &'static [u8] = include_aligned!(Align64, "../user/putchar.elf");
\ No newline at end of file
+pub static CONSOLE: &'static [u8] = include_aligned!(Align64, "../user/console.elf");
\ No newline at end of file
@@ -1,6 +1,6 @@
-use core::{sync::atomic::{AtomicU8, Ordering, AtomicU16, AtomicBool, AtomicU32}};
+use core::sync::atomic::Ordering;
 
-use crate::{consts, service::PutcharQueue};
+use crate::{consts, service::ConsoleChannel, channel::{op, Cqe, Side, Sqe, SQE_SKIP_CQE}, mem::set::MapPermission, syscall::{SysError, DOORBELL_HANDOFF, SYS_CLOSE, SYS_CONNECT, SYS_DOORBELL, SYS_DUP, SYS_EXIT, SYS_GRANT, SYS_LENT, SYS_MAP, SYS_MEM_CREATE, SYS_PIN_HART, SYS_PROC_DUMP, SYS_PROC_INFO, SYS_PROCESS_WAIT, SYS_PUTCHAR, SYS_RESERVE, SYS_REVOKE, SYS_SET_PRIORITY, SYS_SLEEP, SYS_TRANSFER, SYS_UNMAP, SYS_WAIT, SYS_YIELD}};
 
 #[link_section = ".text.vdso"]
 pub extern "C" fn kernel_meow() -> usize {
@@ -11,49 +11,439 @@ pub extern "C" fn kernel_meow() -> usize {
 pub extern "C" fn putchar_sync(c: char) {
     unsafe {
         core::arch::asm!(
-            "li a0, 0x100",
             "ecall",
 
-            in("a1") c as usize
+            inout("a0") SYS_PUTCHAR => _,
+            inout("a1") c as usize => _,
         )
     }
 }
 
+/// Layout of the per-process vDSO data page
+#[repr(C)]
+pub struct VdsoData {
+    /// Base address of the console channel, 0 if not connected yet
+    pub console: usize,
+    pub console_handle: usize,
+    /// Handle to the console service process if our connect started it, 0 otherwise
+    pub console_process: usize,
+    /// Last `user_data` handed out for an entry expecting a completion
+    pub next_user_data: u64,
+    /// Ticks of the `time` CSR per second, filled in by the kernel
+    pub timebase: usize,
+}
+
+#[link_section = ".rodata.vdso"]
+static CONSOLE_SERVICE: [u8; 7] = *b"console";
+
+/// Look up a service in the kernel registry and map a channel to it.
+/// Returns the channel handle and base address, and a handle to the service process if this
+/// connect started it
+#[link_section = ".text.vdso"]
+fn connect(name: &[u8], version: u32) -> Result<(usize, usize, usize), isize> {
+    let err: isize;
+    let handle: usize;
+    let base: usize;
+    let child: usize;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inout("a0") SYS_CONNECT => err,
+            inout("a1") name.as_ptr() => handle,
+            inout("a2") name.len() => base,
+            inout("a3") version as usize => child,
+        );
+    }
+
+    if err == 0 {
+        Ok((handle, base, child))
+    } else {
+        Err(err)
+    }
+}
+
+/// Syscall taking up to three arguments and returning a value or a negative error
+#[link_section = ".text.vdso"]
+#[inline(always)]
+fn syscall3(nr: usize, a1: usize, a2: usize, a3: usize) -> isize {
+    let err: isize;
+    let val: isize;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inout("a0") nr => err,
+            inout("a1") a1 => val,
+            inout("a2") a2 => _,
+            in("a3") a3,
+        );
+    }
+
+    if err == 0 {
+        val
+    } else {
+        err
+    }
+}
+
+/// Block until a doorbell arrives on the channel `handle`, or on any channel if `handle` is 0.
+/// The caller has to set its sleeping flag and recheck the rings before calling this
+#[link_section = ".text.vdso"]
+pub extern "C" fn channel_wait(handle: usize) -> isize {
+    syscall3(SYS_WAIT, handle, 0, 0)
+}
+
+/// Like `channel_wait`, but gives up with `TimedOut` once the `time` CSR reaches `deadline`
+#[link_section = ".text.vdso"]
+pub extern "C" fn channel_wait_until(handle: usize, deadline: usize) -> isize {
+    syscall3(SYS_WAIT, handle, deadline, 0)
+}
+
+/// Wake up the other end of the channel if it is sleeping. With `DOORBELL_HANDOFF` in `flags`,
+/// it runs right away for the rest of the caller's slice.
+/// Returns 1 if it was woken up, 0 if not, or a negative error
+#[link_section = ".text.vdso"]
+pub extern "C" fn channel_doorbell(handle: usize, flags: usize) -> isize {
+    syscall3(SYS_DOORBELL, handle, flags, 0)
+}
+
+/// Lend `pages` pages starting at `start` to the other end of the channel, with the
+/// `MapPermission` bits in `perm`. Returns where they are mapped in the peer, or a negative error
+#[link_section = ".text.vdso"]
+pub extern "C" fn channel_grant(handle: usize, start: usize, pages: usize, perm: usize) -> isize {
+    let err: isize;
+    let peer_base: isize;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inout("a0") SYS_GRANT => err,
+            inout("a1") handle => peer_base,
+            in("a2") start,
+            in("a3") pages,
+            in("a4") perm,
+        );
+    }
+
+    if err == 0 {
+        peer_base
+    } else {
+        err
+    }
+}
+
+/// Take back pages lent with `channel_grant`
+#[link_section = ".text.vdso"]
+pub extern "C" fn channel_revoke(handle: usize, peer_base: usize) -> isize {
+    syscall3(SYS_REVOKE, handle, peer_base, 0)
+}
+
+/// Check that `[addr, addr + len)` lies within pages the other end of the channel lent us for
+/// reading. Returns 0 if so, `InvalidArgument` otherwise
+#[link_section = ".text.vdso"]
+pub extern "C" fn channel_lent(handle: usize, addr: usize, len: usize) -> isize {
+    syscall3(SYS_LENT, handle, addr, len)
+}
+
+/// Drop a handle. A closed channel is unmapped, and the other end sees it as closed
+#[link_section = ".text.vdso"]
+pub extern "C" fn handle_close(handle: usize) -> isize {
+    syscall3(SYS_CLOSE, handle, 0, 0)
+}
+
+/// New handle to the same object with fewer `Rights`. Not for channels, see `Rights::CHANNEL`
+#[link_section = ".text.vdso"]
+pub extern "C" fn handle_dup(handle: usize, rights: usize) -> isize {
+    syscall3(SYS_DUP, handle, rights, 0)
+}
+
+/// Move `handle` to the other end of the channel `chan`. Returns its number there. Channels
+/// move too, unless pages are lent through them
+#[link_section = ".text.vdso"]
+pub extern "C" fn handle_transfer(chan: usize, handle: usize, rights: usize) -> isize {
+    syscall3(SYS_TRANSFER, chan, handle, rights)
+}
+
+/// Map a memory or MMIO handle. Returns the address
+#[link_section = ".text.vdso"]
+pub extern "C" fn handle_map(handle: usize) -> isize {
+    syscall3(SYS_MAP, handle, 0, 0)
+}
+
+#[link_section = ".text.vdso"]
+pub extern "C" fn handle_unmap(addr: usize) -> isize {
+    syscall3(SYS_UNMAP, addr, 0, 0)
+}
+
+/// Allocate zeroed memory that can be mapped and passed around. Returns a handle
+#[link_section = ".text.vdso"]
+pub extern "C" fn mem_create(pages: usize) -> isize {
+    syscall3(SYS_MEM_CREATE, pages, 0, 0)
+}
+
+/// Terminate the calling process. Also where the entry point returns to
+#[link_section = ".text.vdso"]
+pub extern "C" fn exit(code: usize) -> ! {
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a0") SYS_EXIT,
+            in("a1") code,
+            options(noreturn),
+        )
+    }
+}
+
+/// Block until the process behind `handle` exits. Returns its status word, or a negative error
+#[link_section = ".text.vdso"]
+pub extern "C" fn process_wait(handle: usize) -> isize {
+    syscall3(SYS_PROCESS_WAIT, handle, 0, 0)
+}
+
+/// Give up the rest of the time slice to other ready processes, if there are any
+#[link_section = ".text.vdso"]
+pub extern "C" fn yield_now() {
+    syscall3(SYS_YIELD, 0, 0, 0);
+}
+
+/// Set the priority level of the process behind `handle`, or of the caller if 0. Lower levels
+/// run first. Needs the capability to
+#[link_section = ".text.vdso"]
+pub extern "C" fn set_priority(handle: usize, priority: usize) -> isize {
+    syscall3(SYS_SET_PRIORITY, handle, priority, 0)
+}
+
+/// Become a deadline process getting `runtime` out of every `period` ticks of the `time` CSR, or
+/// a best-effort one again with a zero runtime. Needs the capability to
+#[link_section = ".text.vdso"]
+pub extern "C" fn sched_reserve(runtime: usize, period: usize) -> isize {
+    syscall3(SYS_RESERVE, runtime, period, 0)
+}
+
+/// Ticks of the `time` CSR per second, for deadlines and reservations
+#[link_section = ".text.vdso"]
+pub extern "C" fn timebase() -> usize {
+    let data = unsafe { &*(consts::VDSO_DATA as *const VdsoData) };
+    data.timebase
+}
+
+/// Block until the `time` CSR reaches `deadline`
+#[link_section = ".text.vdso"]
+pub extern "C" fn sleep_until(deadline: usize) {
+    syscall3(SYS_SLEEP, deadline, 0, 0);
+}
+
+/// Block for `ticks` ticks of the `time` CSR
+#[link_section = ".text.vdso"]
+pub extern "C" fn sleep_for(ticks: usize) {
+    let now: usize;
+    unsafe {
+        core::arch::asm!("rdtime {}", out(reg) now);
+    }
+    sleep_until(now.saturating_add(ticks));
+}
+
+/// Copy the CPU time and scheduling counters of the process behind `handle`, or of the caller if
+/// 0, into `buf`, at most `len` bytes of them. Returns their full size, or a negative error.
+/// Needs the capability to
+#[link_section = ".text.vdso"]
+pub extern "C" fn proc_info(handle: usize, buf: usize, len: usize) -> isize {
+    syscall3(SYS_PROC_INFO, handle, buf, len)
+}
+
+/// Print the counters of every process to the console. Needs the capability to
+#[link_section = ".text.vdso"]
+pub extern "C" fn proc_dump() -> isize {
+    syscall3(SYS_PROC_DUMP, 0, 0, 0)
+}
+
+/// Have a hart to ourselves, `PIN_ANY` for any free one, or give it back with `PIN_NONE`. Returns
+/// the hart. Needs the capability to
+#[link_section = ".text.vdso"]
+pub extern "C" fn sched_pin_hart(hart: usize) -> isize {
+    syscall3(SYS_PIN_HART, hart, 0, 0)
+}
+
+/// Connected console channel, reconnecting if the service went away
+#[link_section = ".text.vdso"]
+fn console_channel(data: &mut VdsoData) -> Result<&'static ConsoleChannel, isize> {
+    if data.console != 0 && unsafe { &*(data.console as *const ConsoleChannel) }.is_closed() {
+        handle_close(data.console_handle);
+        // Collects its status, if we started it
+        if data.console_process != 0 {
+            handle_close(data.console_process);
+        }
+        data.console = 0;
+    }
+
+    if data.console == 0 {
+        (data.console_handle, data.console, data.console_process) = connect(&CONSOLE_SERVICE, 1)?;
+    }
+
+    Ok(unsafe { &*(data.console as *const ConsoleChannel) })
+}
+
+/// Ring the service if it is idle, so it picks up everything queued so far
+#[link_section = ".text.vdso"]
+fn flush(chan: &ConsoleChannel, handle: usize, flags: usize) {
+    if chan.peer_sleeping(Side::Client) {
+        channel_doorbell(handle, flags);
+    }
+}
+
+/// Queue an entry without ringing the service, sleeping while the ring is full
+#[link_section = ".text.vdso"]
+fn queue(chan: &ConsoleChannel, handle: usize, sqe: Sqe) -> Result<(), isize> {
+    while chan.send(sqe).is_err() {
+        if chan.is_closed() {
+            return Err(SysError::Closed as isize);
+        }
+
+        // The service may be asleep, not knowing about the batch filling the ring
+        flush(chan, handle, DOORBELL_HANDOFF);
+        // Let it drain the ring before resorting to sleep
+        yield_now();
+        if chan.can_send() {
+            continue;
+        }
+
+        if chan.prepare_wait(Side::Client, ConsoleChannel::can_send) {
+            channel_wait(handle);
+            chan.header.client_sleeping.store(false, Ordering::Relaxed);
+        }
+    }
+    Ok(())
+}
+
+/// Wait for the completion of `user_data`, dropping any stale ones before it
+#[link_section = ".text.vdso"]
+fn wait_cqe(chan: &ConsoleChannel, handle: usize, user_data: u64) -> Result<Cqe, isize> {
+    loop {
+        while let Some(cqe) = chan.poll() {
+            if cqe.user_data == user_data {
+                return Ok(cqe);
+            }
+        }
+
+        if chan.is_closed() {
+            return Err(SysError::Closed as isize);
+        }
+
+        if chan.prepare_wait(Side::Client, ConsoleChannel::has_response) {
+            channel_wait(handle);
+            chan.header.client_sleeping.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Fire-and-forget: the service posts no completion
 #[link_section = ".text.vdso"]
 pub extern "C" fn putchar_async(c: char) {
-    // TODO: service table
-    let queue_page_ptr = consts::VDSO_DATA as *mut usize;
-    let mut req_page = unsafe { queue_page_ptr.read() };
-    let mut resp_page: usize = unsafe { queue_page_ptr.offset(1).read() };
+    let data = unsafe { &mut *(consts::VDSO_DATA as *mut VdsoData) };
+    if let Ok(chan) = console_channel(data) {
+        let sqe = Sqe::write(&[c as u8], SQE_SKIP_CQE, 0);
+        if queue(chan, data.console_handle, sqe).is_ok() {
+            flush(chan, data.console_handle, 0);
+        }
+    }
+}
+
+/// Queue `len` bytes as a batch of writes with a single doorbell, then wait for the last one.
+/// Completions come in order, so the last one means everything has been written
+#[link_section = ".text.vdso"]
+fn write_batch(data: &mut VdsoData, buf: &[u8]) -> Result<Cqe, isize> {
+    let chan = console_channel(data)?;
+    data.next_user_data += 1;
+    let user_data = data.next_user_data;
 
-    if req_page == 0 {
-        let mut allocated_req: usize;
-        let mut allocated_resp: usize;
-        unsafe {
-            core::arch::asm!(
-                "li a0, 0x3", // Request service
-                "li a1, 0x0", // Putchar service
-                "ecall",
-                out("a0") allocated_req,
-                out("a1") allocated_resp,
-            );
+    let mut off = 0;
+    loop {
+        let chunk = &buf[off..];
+        off += chunk.len().min(Sqe::INLINE_MAX);
+        let last = off == buf.len();
+        let flags = if last { 0 } else { SQE_SKIP_CQE };
+        queue(chan, data.console_handle, Sqe::write(chunk, flags, user_data))?;
+        if last {
+            break;
         }
+    }
+
+    // About to wait for it anyway
+    flush(chan, data.console_handle, DOORBELL_HANDOFF);
+    wait_cqe(chan, data.console_handle, user_data)
+}
+
+/// Lend the pages under `buf` to the service, have it write them in place, then take them back.
+/// The service can see the rest of those pages too
+#[link_section = ".text.vdso"]
+fn write_granted(data: &mut VdsoData, buf: &[u8]) -> Result<Cqe, isize> {
+    let chan = console_channel(data)?;
+    let handle = data.console_handle;
+
+    let addr = buf.as_ptr() as usize;
+    let start = addr / consts::PAGE_SIZE * consts::PAGE_SIZE;
+    let pages = (addr + buf.len() - start + consts::PAGE_SIZE - 1) / consts::PAGE_SIZE;
+    let peer_base = channel_grant(handle, start, pages, MapPermission::R.bits() as usize);
+    if peer_base < 0 {
+        return Err(peer_base);
+    }
 
-        unsafe {
-            queue_page_ptr.write(allocated_req);
-            queue_page_ptr.offset(1).write(allocated_resp);
+    data.next_user_data += 1;
+    let user_data = data.next_user_data;
+    let sqe = Sqe {
+        opcode: op::WRITE_BUF,
+        flags: 0,
+        _pad: 0,
+        len: buf.len() as u32,
+        user_data,
+        args: [peer_base as u64 + (addr - start) as u64, 0, 0, 0, 0, 0],
+    };
+
+    let result = match queue(chan, handle, sqe) {
+        Ok(()) => {
+            flush(chan, handle, DOORBELL_HANDOFF);
+            wait_cqe(chan, handle, user_data)
         }
+        Err(e) => Err(e),
+    };
+    channel_revoke(handle, peer_base as usize);
+    result
+}
 
-        req_page = allocated_req;
-        resp_page = allocated_resp;
+/// Submit, then wait until the service has handled the character.
+/// Returns the completion result, or a negative error if the service is unreachable
+#[link_section = ".text.vdso"]
+pub extern "C" fn putchar_wait(c: char) -> isize {
+    let data = unsafe { &mut *(consts::VDSO_DATA as *mut VdsoData) };
+    match write_batch(data, &[c as u8]) {
+        Ok(cqe) => cqe.res as isize,
+        Err(e) => e,
     }
+}
 
-    let req_page = unsafe { &mut *(req_page as *mut PutcharQueue) };
-    let cur_trans = req_page.trans.load(Ordering::Relaxed);
-    while cur_trans - req_page.recv.load(Ordering::Acquire) >= 255 {
-        // Spin
+/// Write `len` bytes and wait until they have been written. Returns `len`, or a negative error
+#[link_section = ".text.vdso"]
+pub extern "C" fn console_write(buf: *const u8, len: usize) -> isize {
+    let data = unsafe { &mut *(consts::VDSO_DATA as *mut VdsoData) };
+    if len == 0 {
+        return 0;
     }
 
-    req_page.data[cur_trans as usize % 255] = (cur_trans as u64, c as u64);
-    req_page.trans.fetch_add(1, Ordering::Release);
-}
\ No newline at end of file
+    let buf = unsafe { core::slice::from_raw_parts(buf, len) };
+    // Short writes are cheaper to copy than to map
+    let granted = if len > 4 * Sqe::INLINE_MAX && len <= u32::MAX as usize {
+        write_granted(data, buf)
+    } else {
+        Err(SysError::Unsupported as isize)
+    };
+
+    // Kernel services take no grants, and only memory the process owns can be lent
+    let result = match granted {
+        Ok(cqe) if cqe.res != SysError::Unsupported as i64 => Ok(cqe),
+        _ => write_batch(data, buf),
+    };
+
+    match result {
+        Ok(cqe) if cqe.res < 0 => cqe.res as isize,
+        Ok(_) => len as isize,
+        Err(e) => e,
+    }
+}
@@ -4,6 +4,9 @@ use Result::*;
 enum SPIFunc {
     SetTimer,
     ConsolePutchar,
+    HartStart,
+    RemoteSfenceVma,
+    SendIpi,
 }
 
 impl SPIFunc {
@@ -11,12 +14,16 @@ impl SPIFunc {
         match self {
             SPIFunc::SetTimer => (0x54494D45, 0),
             SPIFunc::ConsolePutchar => (1, 0),
+            SPIFunc::HartStart => (0x48534D, 0),
+            SPIFunc::RemoteSfenceVma => (0x52464E43, 1),
+            SPIFunc::SendIpi => (0x735049, 0),
         }
     }
 }
 
-fn send(func: SPIFunc, mut param0: usize, mut param1: usize) -> Result<usize, usize> {
+fn send(func: SPIFunc, params: [usize; 4]) -> Result<usize, usize> {
     let (eid, fid) = func.id();
+    let [mut param0, mut param1, param2, param3] = params;
     unsafe {
         core::arch::asm!(
             "ecall",
@@ -24,6 +31,8 @@ fn send(func: SPIFunc, mut param0: usize, mut param1: usize) -> Result<usize, us
             in("a6") fid,
             inout("a0") param0,
             inout("a1") param1,
+            in("a2") param2,
+            in("a3") param3,
         );
     }
 
@@ -35,9 +44,26 @@ fn send(func: SPIFunc, mut param0: usize, mut param1: usize) -> Result<usize, us
 }
 
 pub fn console_putchar(c: u8) {
-    send(SPIFunc::ConsolePutchar, c as usize, 0).unwrap();
+    send(SPIFunc::ConsolePutchar, [c as usize, 0, 0, 0]).unwrap();
 }
 
 pub fn set_timer(timer: usize) {
-    send(SPIFunc::SetTimer, timer as usize, 0).unwrap();
+    send(SPIFunc::SetTimer, [timer as usize, 0, 0, 0]).unwrap();
+}
+
+/// Start a stopped hart at `start` with paging off, its hart id in a0 and `opaque` in a1.
+/// Fails for harts that don't exist or are already running
+pub fn hart_start(hartid: usize, start: usize, opaque: usize) -> Result<(), usize> {
+    send(SPIFunc::HartStart, [hartid, start, opaque, 0]).map(|_| ())
+}
+
+/// Flush the whole TLB of the harts in `hart_mask`
+pub fn remote_sfence_vma(hart_mask: usize) {
+    // A size of usize::MAX covers the whole address space
+    send(SPIFunc::RemoteSfenceVma, [hart_mask, 0, 0, usize::MAX]).unwrap();
+}
+
+/// Raise a supervisor software interrupt on the harts in `hart_mask`
+pub fn send_ipi(hart_mask: usize) {
+    send(SPIFunc::SendIpi, [hart_mask, 0, 0, 0]).unwrap();
 }
@@ -0,0 +1,267 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+
+use super::policy::{Enqueue, SchedPolicy};
+use crate::consts::MAX_HARTS;
+use crate::syscall::SysError;
+use crate::{hart, mprintln, timer};
+
+/// Share of the CPU deadline processes may reserve altogether, in parts per million. The rest is
+/// left to best-effort ones
+const MAX_BANDWIDTH: usize = 900_000;
+
+/// Longest period a reservation may have, in seconds. Keeps deadlines and budgets far from
+/// overflowing, since runtimes are at most a period
+const MAX_PERIOD_SECS: usize = 10;
+
+/// A deadline process, run as a constant bandwidth server: it may use `runtime` out of every
+/// `period`, and is throttled until its deadline once the budget for it is used up or it yields
+struct Server {
+    runtime: usize,
+    period: usize,
+    /// `runtime / period` in parts per million, rounded up
+    bandwidth: usize,
+    /// Negative after an overrun, which is paid back from the next replenishments
+    budget: isize,
+    deadline: usize,
+    queued: bool,
+}
+
+impl Server {
+    fn eligible(&self) -> bool {
+        self.queued && self.budget > 0
+    }
+
+    /// New budget and deadline, unless what is left of the current ones would let it use more
+    /// than its bandwidth
+    fn wake(&mut self, now: usize) {
+        let throttled = self.budget <= 0 && self.deadline > now;
+        let left = self.deadline.saturating_sub(now) as u128;
+        let exceeds = self.budget > 0 && self.budget as u128 * self.period as u128 > left * self.runtime as u128;
+        if !throttled && (self.deadline <= now || exceeds) {
+            self.budget = self.runtime as isize;
+            self.deadline = now.saturating_add(self.period);
+        }
+    }
+
+    fn replenish(&mut self, now: usize) {
+        if self.budget <= 0 && self.deadline <= now {
+            self.budget += self.runtime as isize;
+            self.deadline = now.max(self.deadline).saturating_add(self.period);
+        }
+    }
+}
+
+/// Earliest-deadline-first among deadline processes, which always take precedence over the
+/// best-effort ones. Those are left to another policy while no deadline process is eligible.
+///
+/// Budgets are only checked on timer ticks, so an overrun can last up to a slice. It is paid back
+/// by throttling the process longer
+pub struct Edf {
+    best_effort: Box<dyn SchedPolicy>,
+    servers: BTreeMap<usize, Server>,
+    /// Sum of the bandwidth of all servers
+    reserved: usize,
+    /// The server each hart picked last, and since when its time has not been charged yet
+    current: [Option<(usize, usize)>; MAX_HARTS],
+}
+
+impl Edf {
+    pub fn new(best_effort: Box<dyn SchedPolicy>) -> Self {
+        Self {
+            best_effort,
+            servers: BTreeMap::new(),
+            reserved: 0,
+            current: [None; MAX_HARTS],
+        }
+    }
+
+    /// Take the time the current server of this hart has run off its budget, and replenish
+    /// throttled ones that reached their deadline
+    fn account(&mut self, now: usize) {
+        let current = &mut self.current[hart::id()];
+        if let Some((pid, since)) = *current {
+            if let Some(server) = self.servers.get_mut(&pid) {
+                server.budget -= (now - since) as isize;
+            }
+            *current = Some((pid, now));
+        }
+
+        for server in self.servers.values_mut() {
+            server.replenish(now);
+        }
+    }
+
+    /// Eligible server with the earliest deadline
+    fn earliest(&self) -> Option<(usize, usize)> {
+        self.servers
+            .iter()
+            .filter(|(_, server)| server.eligible())
+            .map(|(&pid, server)| (pid, server.deadline))
+            .min_by_key(|&(_, deadline)| deadline)
+    }
+}
+
+impl SchedPolicy for Edf {
+    fn enqueue(&mut self, pid: usize, priority: usize, why: Enqueue) {
+        if !self.servers.contains_key(&pid) {
+            return self.best_effort.enqueue(pid, priority, why);
+        }
+
+        let now = timer::rtc();
+        if why == Enqueue::Yielded {
+            // Charge it up to here, so none of that comes off the next budget
+            self.account(now);
+        }
+        let server = self.servers.get_mut(&pid).unwrap();
+        match why {
+            Enqueue::New | Enqueue::Woken => server.wake(now),
+            // Done for this period, as with sched_yield under SCHED_DEADLINE. Otherwise the
+            // earliest deadline would hand it the CPU straight back
+            Enqueue::Yielded => server.budget = server.budget.min(0),
+            Enqueue::Preempted | Enqueue::HandedOff => {}
+        }
+        server.queued = true;
+    }
+
+    fn dequeue(&mut self, pid: usize) {
+        if let Some(server) = self.servers.remove(&pid) {
+            self.reserved -= server.bandwidth;
+        }
+        for current in self.current.iter_mut() {
+            if current.map(|(current, _)| current) == Some(pid) {
+                *current = None;
+            }
+        }
+        self.best_effort.dequeue(pid);
+    }
+
+    fn reprioritize(&mut self, pid: usize, priority: usize) {
+        // Deadline processes have no priority, it applies once they are best-effort again
+        self.best_effort.reprioritize(pid, priority);
+    }
+
+    fn pick_next(&mut self) -> Option<usize> {
+        let now = timer::rtc();
+        self.account(now);
+        self.current[hart::id()] = None;
+
+        if let Some((pid, _)) = self.earliest() {
+            self.servers.get_mut(&pid).unwrap().queued = false;
+            self.current[hart::id()] = Some((pid, now));
+            return Some(pid);
+        }
+        self.best_effort.pick_next()
+    }
+
+    fn take(&mut self, pid: usize) -> bool {
+        let now = timer::rtc();
+        // Whatever ran before is charged up to here, and servers due are replenished before
+        // checking whether any should run first
+        self.account(now);
+        let earliest = self.earliest().map(|(_, deadline)| deadline);
+        match self.servers.get(&pid) {
+            Some(server) if server.eligible() && earliest.map_or(true, |d| d >= server.deadline) => {
+                self.servers.get_mut(&pid).unwrap().queued = false;
+                self.current[hart::id()] = Some((pid, now));
+                true
+            }
+            // Throttled, it has to wait for its deadline like everybody else. Nor do handoffs get
+            // ahead of an earlier deadline
+            Some(_) => false,
+            // Eligible servers always go before best-effort processes
+            None if earliest.is_some() => false,
+            None => {
+                let taken = self.best_effort.take(pid);
+                if taken {
+                    self.current[hart::id()] = None;
+                }
+                taken
+            }
+        }
+    }
+
+    fn on_tick(&mut self, running: Option<usize>) -> bool {
+        let now = timer::rtc();
+        // Reserved while running, so it was not picked as a server
+        if let Some(pid) = running.filter(|pid| self.servers.contains_key(pid)) {
+            let current = &mut self.current[hart::id()];
+            if current.map(|(current, _)| current) != Some(pid) {
+                *current = Some((pid, now));
+            }
+        }
+        self.account(now);
+        let earliest = self.earliest();
+
+        match running.and_then(|pid| self.servers.get(&pid)) {
+            Some(server) => {
+                let preempt = server.budget <= 0
+                    || earliest.map_or(false, |(_, deadline)| deadline < server.deadline);
+                // None of its processes is running, but its clock keeps going, e.g. for boosts
+                self.best_effort.on_tick(None);
+                preempt
+            }
+            None => {
+                let preempt = self.best_effort.on_tick(running);
+                preempt || earliest.is_some()
+            }
+        }
+    }
+
+    fn wants_tick(&self, running: Option<usize>) -> bool {
+        // Budgets are charged and replenished on ticks, throttled servers included
+        running.map_or(false, |pid| self.servers.contains_key(&pid))
+            || self.servers.values().any(|server| server.queued)
+            || self.best_effort.wants_tick(running)
+    }
+
+    fn reserve(
+        &mut self,
+        pid: usize,
+        priority: usize,
+        ready: bool,
+        runtime: usize,
+        period: usize,
+    ) -> Result<(), SysError> {
+        let old = self.servers.get(&pid).map_or(0, |server| server.bandwidth);
+        if runtime == 0 {
+            if let Some(server) = self.servers.remove(&pid) {
+                self.reserved -= old;
+                if server.queued {
+                    self.best_effort.enqueue(pid, priority, Enqueue::New);
+                }
+            }
+            return Ok(());
+        }
+
+        if period > timer::timebase() * MAX_PERIOD_SECS {
+            return Err(SysError::InvalidArgument);
+        }
+        let bandwidth = ((runtime as u128 * 1_000_000 + period as u128 - 1) / period as u128) as usize;
+        if self.reserved - old + bandwidth > MAX_BANDWIDTH {
+            mprintln!("[Sched] Rejecting reservation of {}/{} for {}", runtime, period, pid);
+            return Err(SysError::Busy);
+        }
+        self.reserved = self.reserved - old + bandwidth;
+
+        let queued = match self.servers.remove(&pid) {
+            Some(server) => server.queued,
+            None => {
+                self.best_effort.dequeue(pid);
+                ready
+            }
+        };
+        self.servers.insert(
+            pid,
+            Server {
+                runtime,
+                period,
+                bandwidth,
+                budget: runtime as isize,
+                deadline: timer::rtc().saturating_add(period),
+                queued,
+            },
+        );
+        Ok(())
+    }
+}
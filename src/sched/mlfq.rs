@@ -0,0 +1,113 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+
+use super::policy::{Enqueue, SchedPolicy};
+use super::PRIORITY_LEVELS;
+use crate::mprintln;
+
+/// Preemptions between resetting every process to its base priority, so demoted ones don't starve
+const BOOST_INTERVAL: usize = 100;
+
+struct Level {
+    /// Base priority, the highest level the process gets back to
+    priority: usize,
+    current: usize,
+}
+
+/// Round-robin within each priority level, lower levels first. Processes using up their slice
+/// sink a level, and blocking brings them back up, so those that mostly wait on others, like
+/// services, get to run first
+#[derive(Default)]
+pub struct Mlfq {
+    ready: [VecDeque<usize>; PRIORITY_LEVELS],
+    /// Every process known to the policy
+    levels: BTreeMap<usize, Level>,
+    /// Preemptions since the last priority boost
+    since_boost: usize,
+}
+
+impl Mlfq {
+    /// Move every process back to its base level
+    fn boost(&mut self) {
+        mprintln!("[Sched] Boosting priorities");
+        self.since_boost = 0;
+        for level in self.levels.values_mut() {
+            level.current = level.priority;
+        }
+
+        let queued: Vec<_> = self.ready.iter_mut().flat_map(|queue| queue.drain(..)).collect();
+        for pid in queued {
+            self.ready[self.levels[&pid].current].push_back(pid);
+        }
+    }
+}
+
+impl SchedPolicy for Mlfq {
+    fn enqueue(&mut self, pid: usize, priority: usize, why: Enqueue) {
+        let level = self.levels.entry(pid).or_insert(Level {
+            priority,
+            current: priority,
+        });
+
+        match why {
+            // Gave up the CPU before its slice ran out
+            Enqueue::New | Enqueue::Woken => level.current = priority,
+            Enqueue::Preempted => level.current = (level.current + 1).min(PRIORITY_LEVELS - 1),
+            Enqueue::Yielded | Enqueue::HandedOff => {}
+        }
+        self.ready[level.current].push_back(pid);
+    }
+
+    fn dequeue(&mut self, pid: usize) {
+        if let Some(level) = self.levels.remove(&pid) {
+            self.ready[level.current].retain(|&queued| queued != pid);
+        }
+    }
+
+    fn reprioritize(&mut self, pid: usize, priority: usize) {
+        let level = match self.levels.get_mut(&pid) {
+            Some(level) => level,
+            None => return,
+        };
+
+        // Moved there right away
+        let queue = &mut self.ready[level.current];
+        let queued = queue.iter().position(|&other| other == pid);
+        if let Some(idx) = queued {
+            queue.remove(idx);
+        }
+        level.priority = priority;
+        level.current = priority;
+        if queued.is_some() {
+            self.ready[priority].push_back(pid);
+        }
+    }
+
+    fn pick_next(&mut self) -> Option<usize> {
+        self.ready.iter_mut().find_map(VecDeque::pop_front)
+    }
+
+    fn take(&mut self, pid: usize) -> bool {
+        let queue = match self.levels.get(&pid) {
+            Some(level) => &mut self.ready[level.current],
+            None => return false,
+        };
+        match queue.iter().position(|&queued| queued == pid) {
+            Some(idx) => queue.remove(idx).is_some(),
+            None => false,
+        }
+    }
+
+    fn on_tick(&mut self, _running: Option<usize>) -> bool {
+        self.since_boost += 1;
+        if self.since_boost >= BOOST_INTERVAL {
+            self.boost();
+        }
+        true
+    }
+
+    fn wants_tick(&self, _running: Option<usize>) -> bool {
+        // Boosts only matter with others around
+        self.ready.iter().any(|queue| !queue.is_empty())
+    }
+}
@@ -0,0 +1,866 @@
+use core::fmt::Write;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use riscv::register::sstatus;
+use spin::Mutex;
+
+use crate::channel::Endpoint;
+use crate::consts::{MAX_HARTS, PAGE_SIZE};
+use crate::mem::set::MemorySet;
+use crate::process::handle::{KObject, Rights};
+use crate::process::{Grant, Process};
+use crate::syscall::SysError;
+use crate::timer::TimerId;
+use crate::trap::TrapFrame;
+use crate::{hart, mprintln, prog, timer, uprint, uprintln};
+
+mod edf;
+#[cfg(not(feature = "sched-rr"))]
+mod mlfq;
+pub mod policy;
+#[cfg(feature = "sched-rr")]
+mod rr;
+pub mod stats;
+
+use policy::{Enqueue, SchedPolicy};
+use stats::ProcInfo;
+
+lazy_static! {
+    pub static ref SCHEDULER: Mutex<Sched> = Mutex::new(Sched::new());
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProcState {
+    /// In the ready queue
+    Ready,
+    Running,
+    /// Sitting on one or more wait queues
+    Blocked,
+    /// Torn down, waiting to be switched away from
+    Exited,
+}
+
+/// Something processes can block on
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum WaitKey {
+    /// Doorbells on a channel, and it being closed. Identified by the frame of its header
+    Channel(usize),
+    /// A process exiting, by pid
+    Exit(usize),
+}
+
+/// What a kernel process asks of the scheduler, see `kernel_wait` and `kernel_yield`
+#[derive(Clone, Copy, Debug)]
+pub enum KernelRequest {
+    Yield,
+    /// Like `channel_wait_until` on all of its channels, 0 waiting forever
+    Wait(usize),
+}
+
+/// How a process ended
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExitStatus {
+    /// Passed to exit, or returned from the entry point
+    Exited(u32),
+    /// Killed on an exception it could not handle, with its scause
+    Faulted(usize),
+}
+
+impl ExitStatus {
+    /// Set in the status word of processes killed on a fault
+    pub const FAULTED: usize = 1 << 32;
+
+    /// Status word handed to user space: the exit code, or the cause with `FAULTED` set
+    pub fn encode(self) -> usize {
+        match self {
+            ExitStatus::Exited(code) => code as usize,
+            ExitStatus::Faulted(cause) => Self::FAULTED | cause as u32 as usize,
+        }
+    }
+}
+
+/// Number of priority levels. Level 0 runs first, where the policy cares about priorities
+pub const PRIORITY_LEVELS: usize = 4;
+pub const DEFAULT_PRIORITY: usize = 1;
+
+/// Processes blocked on one key, in the order they went to sleep
+#[derive(Default)]
+pub struct WaitQueue {
+    waiters: VecDeque<usize>,
+}
+
+static mut IDLE_STACKS: [[u8; PAGE_SIZE]; MAX_HARTS] = [[0; PAGE_SIZE]; MAX_HARTS];
+
+/// Runs `wfi` with interrupts enabled while nothing is ready. It is not a process: it has no pid,
+/// never blocks and is never queued
+struct Idle {
+    tf: TrapFrame,
+    /// Kernel mappings only, so exited processes can be torn down while idling
+    mset: MemorySet,
+    /// When the current idle stretch began, in `time` CSR ticks
+    since: Option<usize>,
+    /// Idle ticks before the current stretch
+    total: usize,
+}
+
+impl Idle {
+    /// Counts as idling from the start, until the hart picks its first process
+    fn new(hart: usize) -> Self {
+        // Keep sp 16-byte aligned
+        let stack_top = unsafe { IDLE_STACKS[hart].as_ptr() as usize + PAGE_SIZE } & !0xf;
+        let mut tf = TrapFrame::with_process(false, prog::idle as usize, stack_top);
+        tf.x[4] = hart;
+        Self {
+            tf,
+            mset: MemorySet::new_kernel().expect("No memory for idle page table"),
+            since: Some(timer::rtc()),
+            total: 0,
+        }
+    }
+
+    fn time(&self) -> usize {
+        self.total + self.since.map_or(0, |since| timer::rtc() - since)
+    }
+}
+
+/// What a hart is up to
+#[derive(Default)]
+struct Hart {
+    /// None while idling
+    running: Option<usize>,
+    /// Set up by `bootstrap`
+    idle: Option<Idle>,
+    /// The only process this hart runs, see `Sched::pin`
+    dedicated: Option<usize>,
+    /// Whether its slice timer is armed. Harts go tickless while nothing could preempt them
+    ticking: bool,
+}
+
+pub struct Sched {
+    processes: BTreeMap<usize, Process>,
+    /// Holds the ready processes and decides which one runs next
+    policy: Box<dyn SchedPolicy>,
+    /// Only keys somebody waits on have an entry
+    queues: BTreeMap<WaitKey, WaitQueue>,
+    /// Status of exited processes that others still hold handles to, by pid. Parents hold one to
+    /// each child they spawn
+    exited: BTreeMap<usize, ExitStatus>,
+    /// Indexed by hart id
+    harts: [Hart; MAX_HARTS],
+    next_pid: AtomicUsize,
+}
+
+impl Sched {
+    pub fn new() -> Self {
+        Self {
+            processes: BTreeMap::new(),
+            policy: policy::new(),
+            queues: BTreeMap::new(),
+            exited: BTreeMap::new(),
+            harts: Default::default(),
+            next_pid: AtomicUsize::new(1),
+        }
+    }
+
+    /// Switch away from the running process on preemption, or when it yields with `involuntary`
+    /// unset. Where it gets queued and what runs next is up to the policy; a yield with nothing
+    /// else ready keeps running
+    pub fn tick(&mut self, involuntary: bool, tf: &mut TrapFrame) {
+        if self.hart().idle.is_none() {
+            mprintln!("[Sched] Not bootstrapped yet");
+            return;
+        }
+
+        let prev = self.hart().running;
+        if involuntary {
+            let preempt = match self.hart().dedicated {
+                // Never preempted, and woken up by `kick` rather than the timer
+                Some(pinned) => prev.map_or(false, |pid| pid != pinned),
+                None => self.policy.on_tick(prev),
+            };
+            if !preempt {
+                self.arm_tick(true);
+                return;
+            }
+        }
+
+        mprintln!("[Sched] Currently running on {}: {:?}", hart::id(), prev);
+        // The idle loop has no state worth saving
+        if let Some(running) = prev {
+            self.save_running(tf);
+            let why = if involuntary { Enqueue::Preempted } else { Enqueue::Yielded };
+            self.make_ready(running, why);
+        }
+
+        self.switch_next(tf);
+        if involuntary || self.hart().running != prev {
+            // A full slice for the next process, not what is left of the yielding one
+            self.arm_tick(true);
+        }
+        if self.hart().running == prev {
+            return;
+        }
+        if let Some(prev) = prev {
+            let stats = &mut self.processes.get_mut(&prev).unwrap().stats;
+            if involuntary {
+                stats.involuntary += 1;
+            } else {
+                stats.voluntary += 1;
+            }
+        }
+    }
+
+    /// Keep ticking while the process running here may have to make room for another one, and
+    /// stop otherwise. `fresh` starts a new slice even if it is ticking already
+    fn arm_tick(&mut self, fresh: bool) {
+        let hart = &self.harts[hart::id()];
+        let ticking = hart.dedicated.is_none() && self.policy.wants_tick(hart.running);
+        if fresh || ticking != hart.ticking {
+            if ticking {
+                timer::rearm();
+            } else {
+                timer::disarm();
+            }
+            self.hart().ticking = ticking;
+        }
+    }
+
+    /// Something was queued that tickless harts would not notice: have an idle one pick it up, or
+    /// else the busy ones start preempting
+    fn notify_queued(&mut self) {
+        let general = |hart: &Hart| hart.idle.is_some() && hart.dedicated.is_none();
+        let idle = self.harts.iter().position(|hart| general(hart) && hart.running.is_none());
+        match idle {
+            // Idling here means firing timers, which picks it up afterwards
+            Some(id) if id == hart::id() => {}
+            Some(id) => hart::kick(id),
+            None => {
+                for id in 0..MAX_HARTS {
+                    if !general(&self.harts[id]) || self.harts[id].ticking {
+                        continue;
+                    }
+                    if id == hart::id() {
+                        self.arm_tick(false);
+                    } else {
+                        hart::kick(id);
+                    }
+                }
+            }
+        }
+    }
+
+    fn save_running(&mut self, tf: &TrapFrame) {
+        self.running_process().tf = tf.clone();
+    }
+
+    fn make_ready(&mut self, pid: usize, why: Enqueue) {
+        let proc = self.processes.get_mut(&pid).unwrap();
+        proc.state = ProcState::Ready;
+        if why == Enqueue::Woken {
+            proc.stats.woken(timer::rtc());
+        }
+        let priority = proc.priority;
+        match self.pinned_hart(pid) {
+            // Its hart idles meanwhile, or is still busy evicting whatever ran there before
+            Some(hart) => {
+                if self.harts[hart].running != Some(pid) {
+                    hart::kick(hart);
+                }
+            }
+            None => {
+                self.policy.enqueue(pid, priority, why);
+                // Preempted or yielding ones are up for grabs on this hart right away
+                if let Enqueue::New | Enqueue::Woken = why {
+                    self.notify_queued();
+                }
+            }
+        }
+    }
+
+    fn pinned_hart(&self, pid: usize) -> Option<usize> {
+        self.harts.iter().position(|hart| hart.dedicated == Some(pid))
+    }
+
+    /// Dedicate a hart to `pid`, any free one if `hart` is None. It runs there alone, busy polling
+    /// without ever being preempted, and the hart idles while it is blocked. At least one hart is
+    /// always left to everything else. Returns the hart; the process moves there when it next
+    /// leaves the CPU. Any hart it was pinned to before is given back, even if this fails
+    pub fn pin(&mut self, pid: usize, hart: Option<usize>) -> Result<usize, SysError> {
+        self.unpin(pid);
+
+        let free = |sched: &Self, id: usize| hart::is_online(id) && sched.harts[id].dedicated.is_none();
+        let target = match hart {
+            Some(id) if !hart::is_online(id) => return Err(SysError::InvalidArgument),
+            Some(id) => Some(id).filter(|&id| free(self, id)),
+            None => (0..MAX_HARTS).rev().find(|&id| free(self, id)),
+        }
+        .ok_or(SysError::Busy)?;
+        if !(0..MAX_HARTS).any(|id| id != target && free(self, id)) {
+            return Err(SysError::Busy);
+        }
+
+        mprintln!("[Sched] Pinning {} to {}", pid, target);
+        // Drops any reservation, the process has a whole hart now
+        self.policy.dequeue(pid);
+        self.harts[target].dedicated = Some(pid);
+        timer::dedicate(target, Some(pid));
+        Ok(target)
+    }
+
+    /// Give the hart dedicated to `pid` back to everyone. If `pid` is ready, it stays off the
+    /// policy until it next leaves the CPU
+    pub fn unpin(&mut self, pid: usize) {
+        let id = match self.pinned_hart(pid) {
+            Some(id) => id,
+            None => return,
+        };
+
+        mprintln!("[Sched] Unpinning {} from {}", pid, id);
+        self.harts[id].dedicated = None;
+        timer::dedicate(id, None);
+        // Otherwise the process leaving the CPU rearms it
+        if id != hart::id() {
+            hart::kick(id);
+        }
+    }
+
+    /// Serve what the running kernel process asked for before raising the software interrupt that
+    /// brought us here. Returns false if there was nothing, or nothing left to do
+    fn kernel_request(&mut self, tf: &mut TrapFrame) -> bool {
+        let request = match self.hart().running {
+            Some(_) => self.running_process().kernel_request.take(),
+            None => None,
+        };
+        match request {
+            Some(KernelRequest::Yield) => self.tick(false, tf),
+            Some(KernelRequest::Wait(deadline)) => {
+                let proc = self.running_process();
+                // A doorbell arrived or a channel closed since the flags were set, or it is due
+                if !proc.wait_armed(0) || (deadline != 0 && deadline <= timer::rtc()) {
+                    return false;
+                }
+                let keys = proc.handles.channels().map(|(_, ep)| ep.wait_key()).collect();
+                self.block_on(keys, Some(deadline).filter(|&deadline| deadline != 0), tf);
+            }
+            None => return false,
+        }
+        true
+    }
+
+    /// Something this hart may want to run became ready: another hart interrupted it because the
+    /// process dedicated to it is ready, because work was queued while it idled or ran tickless, or
+    /// because the hart was given back. Or a timer fired
+    pub fn kick(&mut self, tf: &mut TrapFrame) {
+        if self.hart().idle.is_none() {
+            return;
+        }
+
+        let running = self.hart().running;
+        match self.hart().dedicated {
+            Some(pinned) if running != Some(pinned) => {
+                if self.processes[&pinned].state != ProcState::Ready {
+                    // Blocked again already, or was woken before it ever ran here
+                    return;
+                }
+                // Left over from before the hart was dedicated
+                if let Some(running) = running {
+                    self.save_running(tf);
+                    self.running_process().stats.involuntary += 1;
+                    self.make_ready(running, Enqueue::HandedOff);
+                    // This hart won't be back for it, so another one has to notice
+                    self.notify_queued();
+                }
+                self.switch_to(Some(pinned), tf);
+            }
+            Some(_) => {}
+            None if running.is_none() => self.switch_next(tf),
+            None => self.arm_tick(false),
+        }
+    }
+
+    /// Change the base priority of a process
+    pub fn set_priority(&mut self, pid: usize, priority: usize) -> Result<(), SysError> {
+        if priority >= PRIORITY_LEVELS {
+            return Err(SysError::InvalidArgument);
+        }
+
+        self.processes.get_mut(&pid).ok_or(SysError::NotFound)?.priority = priority;
+        self.policy.reprioritize(pid, priority);
+        Ok(())
+    }
+
+    /// Make a process a deadline process, getting `runtime` out of every `period` ticks of the
+    /// `time` CSR, or back into a best-effort one if `runtime` is 0. Fails with `Busy` if that
+    /// would overcommit the CPU
+    pub fn reserve(&mut self, pid: usize, runtime: usize, period: usize) -> Result<(), SysError> {
+        if runtime > period {
+            return Err(SysError::InvalidArgument);
+        }
+        // Has a hart to itself already
+        if self.pinned_hart(pid).is_some() {
+            return Err(SysError::Busy);
+        }
+
+        let proc = self.processes.get(&pid).ok_or(SysError::NotFound)?;
+        let ready = proc.state == ProcState::Ready;
+        self.policy.reserve(pid, proc.priority, ready, runtime, period)
+    }
+
+    /// Load the process picked by the policy, or the idle loop if there is none. Dedicated harts
+    /// only ever run their own process
+    fn switch_next(&mut self, tf: &mut TrapFrame) {
+        let pid = match self.hart().dedicated {
+            Some(pinned) => Some(pinned).filter(|pid| self.processes[pid].state == ProcState::Ready),
+            None => self.policy.pick_next(),
+        };
+        self.switch_to(pid, tf);
+    }
+
+    fn switch_to(&mut self, pid: Option<usize>, tf: &mut TrapFrame) {
+        let (user, kernel) = stats::take();
+        let hart = &mut self.harts[hart::id()];
+        if let Some(prev) = hart.running {
+            let stats = &mut self.processes.get_mut(&prev).unwrap().stats;
+            stats.user_time += user;
+            stats.kernel_time += kernel;
+        }
+
+        let idle = hart.idle.as_mut().unwrap();
+        let now = timer::rtc();
+        match (hart.running, pid) {
+            (None, None) => {}
+            (None, Some(_)) => idle.total += now - idle.since.take().unwrap(),
+            (Some(_), None) => idle.since = Some(now),
+            (Some(_), Some(_)) => {}
+        }
+
+        hart.running = pid;
+        mprintln!("[Sched] Switching {} to: {:?}", hart::id(), pid);
+        match pid {
+            Some(pid) => {
+                let next = self.processes.get_mut(&pid).unwrap();
+                next.state = ProcState::Running;
+                next.stats.scheduled(now);
+                next.mset.activate();
+                *tf = next.tf.clone();
+                if !next.user {
+                    // Kernel code relies on tp, and the process may have run elsewhere before
+                    tf.x[4] = hart::id();
+                }
+            }
+            None => {
+                idle.mset.activate();
+                *tf = idle.tf.clone();
+            }
+        }
+        self.arm_tick(false);
+    }
+
+    /// Run `pid`, which was just woken up, in place of the running process. It gets the rest of
+    /// the current slice, and the running process goes back to the ready queue
+    pub fn handoff(&mut self, pid: usize, tf: &mut TrapFrame) {
+        // Nothing else may run on a dedicated hart
+        if self.hart().dedicated.is_some() || !self.policy.take(pid) {
+            return;
+        }
+
+        mprintln!("[Sched] Handing off to: {}", pid);
+        let running = self.running_pid();
+        self.save_running(tf);
+        self.running_process().stats.voluntary += 1;
+        self.make_ready(running, Enqueue::HandedOff);
+        self.switch_to(Some(pid), tf);
+    }
+
+    /// Tear down the running process and switch away from it. The status goes to whoever is
+    /// waiting on it, and is kept until the last handle to the process is closed
+    pub fn exit(&mut self, status: ExitStatus, tf: &mut TrapFrame) {
+        let pid = self.running_pid();
+        mprintln!("[Sched] Exiting: {} with {:?}", pid, status);
+        let proc = self.processes.get_mut(&pid).unwrap();
+        proc.state = ProcState::Exited;
+        self.policy.dequeue(pid);
+        let handles: Vec<_> = proc.handles.drain().collect();
+        let grants: Vec<_> = proc.grants.drain(..).collect();
+        self.unpin(pid);
+        for child in self.processes.values_mut().filter(|proc| proc.parent == pid) {
+            child.parent = 0;
+        }
+
+        // Dropping our handles collects the children that exited before us
+        for handle in handles {
+            match handle.object {
+                KObject::Channel(ep) => self.close_endpoint(ep),
+                KObject::Process(held) => self.release_exited(held),
+                _ => {}
+            }
+        }
+        // The frames are freed along with the process
+        if !grants.is_empty() {
+            for grant in grants {
+                self.revoke(grant);
+            }
+            hart::shootdown();
+        }
+
+        if let Some(queue) = self.queues.remove(&WaitKey::Exit(pid)) {
+            for waiter in queue.waiters {
+                // Woken up with the result of the syscall it blocked in
+                self.processes.get_mut(&waiter).unwrap().tf.x[11] = status.encode();
+                self.wake(waiter);
+            }
+        }
+
+        // A zombie until the parent and whoever else holds a handle have collected the status
+        if self.is_held(pid) {
+            self.exited.insert(pid, status);
+        } else {
+            uprintln!("[Sched] process {} exited: {:?}", pid, status);
+        }
+
+        self.switch_next(tf);
+
+        // Only now that its page table is no longer active
+        self.processes.remove(&pid);
+    }
+
+    /// Mark the channel closed, waking the other end if it is waiting on it
+    pub fn close_endpoint(&mut self, ep: Endpoint) {
+        if ep.close() {
+            self.wake_queue(ep.wait_key());
+        }
+    }
+
+    /// Unmap lent pages from the peer, if it is still around
+    pub fn revoke(&mut self, grant: Grant) {
+        if let Some(peer) = self.processes.get_mut(&grant.peer) {
+            let unmapped = peer.unmap_grant(grant.peer_base, grant.pages);
+            assert!(unmapped, "Grant at {:#x} not mapped", grant.peer_base);
+        }
+    }
+
+    /// Take the running process off the CPU and put it on the queues of `keys`, until any of them
+    /// is woken up or `deadline` passes. Sleeping processes wait on no key at all
+    pub fn block_on(&mut self, keys: Vec<WaitKey>, deadline: Option<usize>, tf: &mut TrapFrame) {
+        let pid = self.running_pid();
+        mprintln!("[Sched] Blocking: {} on {:?} until {:?}", pid, keys, deadline);
+        for &key in keys.iter() {
+            self.queues.entry(key).or_default().waiters.push_back(pid);
+        }
+        let timeout = deadline.map(|deadline| {
+            timer::add(
+                deadline,
+                pid,
+                Box::new(move |id| SCHEDULER.lock().time_out(pid, id)),
+            )
+        });
+        let proc = self.running_process();
+        proc.state = ProcState::Blocked;
+        proc.waits = keys;
+        proc.timeout = timeout;
+        proc.stats.voluntary += 1;
+        self.save_running(tf);
+        self.switch_next(tf);
+    }
+
+    /// Make a blocked process ready again, taking it off every queue it sits on
+    pub fn wake(&mut self, pid: usize) {
+        let proc = match self.processes.get_mut(&pid) {
+            Some(proc) if proc.state == ProcState::Blocked => proc,
+            _ => return,
+        };
+
+        mprintln!("[Sched] Waking: {}", pid);
+        if let Some(id) = proc.timeout.take() {
+            timer::cancel(id);
+        }
+        for key in core::mem::take(&mut proc.waits) {
+            if let Some(queue) = self.queues.get_mut(&key) {
+                queue.waiters.retain(|&waiter| waiter != pid);
+                if queue.waiters.is_empty() {
+                    self.queues.remove(&key);
+                }
+            }
+        }
+        self.make_ready(pid, Enqueue::Woken);
+    }
+
+    /// The deadline `pid` blocked with passed. Waits fail with `TimedOut`, while sleeps are done
+    fn time_out(&mut self, pid: usize, id: TimerId) {
+        let proc = match self.processes.get_mut(&pid) {
+            // Woken up some other way already, just as the timer fired
+            Some(proc) if proc.timeout == Some(id) => proc,
+            _ => return,
+        };
+
+        mprintln!("[Sched] Timed out: {}", pid);
+        proc.timeout = None;
+        // Kernel processes were interrupted wherever they asked, so a0 is theirs
+        if !proc.waits.is_empty() && proc.user {
+            proc.tf.x[10] = SysError::TimedOut as isize as usize;
+        }
+        self.wake(pid);
+    }
+
+    /// Whether any process holds a handle to `pid`
+    fn is_held(&self, pid: usize) -> bool {
+        self.processes.values().any(|proc| proc.handles.refers_to(pid))
+    }
+
+    /// A handle to `pid` was dropped. Forget its status if it has exited and that was the last one
+    pub fn release_exited(&mut self, pid: usize) {
+        if self.exited.contains_key(&pid) && !self.is_held(pid) {
+            self.exited.remove(&pid);
+        }
+    }
+
+    /// Wake up everything waiting on `key`
+    pub fn wake_queue(&mut self, key: WaitKey) {
+        if let Some(queue) = self.queues.remove(&key) {
+            for pid in queue.waiters {
+                self.wake(pid);
+            }
+        }
+    }
+
+    fn hart(&mut self) -> &mut Hart {
+        &mut self.harts[hart::id()]
+    }
+
+    /// Process running on the current hart. Only valid from syscalls and faults, which never come
+    /// from the idle loop
+    pub fn running_process(&mut self) -> &mut Process {
+        let pid = self.running_pid();
+        self.processes.get_mut(&pid).unwrap()
+    }
+
+    pub fn running_pid(&self) -> usize {
+        self.harts[hart::id()].running.unwrap()
+    }
+
+    /// Counters of `pid`. Those of a process running on another hart lack the time since it last
+    /// trapped there
+    pub fn info(&mut self, pid: usize) -> Option<ProcInfo> {
+        if self.hart().running == Some(pid) {
+            let (user, kernel) = stats::take();
+            let stats = &mut self.processes.get_mut(&pid).unwrap().stats;
+            stats.user_time += user;
+            stats.kernel_time += kernel;
+        }
+
+        let proc = self.processes.get(&pid)?;
+        let stats = &proc.stats;
+        Some(ProcInfo {
+            pid,
+            state: proc.state as usize,
+            priority: proc.priority,
+            user_time: stats.user_time,
+            kernel_time: stats.kernel_time,
+            voluntary: stats.voluntary,
+            involuntary: stats.involuntary,
+            wakeups: stats.wakeups,
+            wakeup_latency: stats.wakeup_latency,
+            max_wakeup_latency: stats.max_wakeup_latency,
+            parent: proc.parent,
+        })
+    }
+
+    /// The counters of every process, in `time` CSR ticks, formatted for the console. Printing
+    /// is left to the caller, so the scheduler lock isn't held for that long
+    pub fn dump(&mut self) -> String {
+        let mut out = String::new();
+        let pids: Vec<_> = self.processes.keys().copied().collect();
+        writeln!(
+            out,
+            "[Sched] {:>4} {:>8} {:>3} {:>12} {:>12} {:>6} {:>6} {:>10} {:>10}",
+            "pid", "state", "pri", "user", "kernel", "vol", "invol", "avg wake", "max wake"
+        )
+        .unwrap();
+        for pid in pids {
+            let info = self.info(pid).unwrap();
+            let state = self.processes[&pid].state;
+            writeln!(
+                out,
+                "[Sched] {:>4} {:>8} {:>3} {:>12} {:>12} {:>6} {:>6} {:>10} {:>10}",
+                pid,
+                alloc::format!("{:?}", state),
+                info.priority,
+                info.user_time,
+                info.kernel_time,
+                info.voluntary,
+                info.involuntary,
+                info.wakeup_latency / info.wakeups.max(1),
+                info.max_wakeup_latency,
+            )
+            .unwrap();
+        }
+        writeln!(out, "[Sched] idle {}", self.idle_time()).unwrap();
+        out
+    }
+
+    /// Total time spent idling since bootstrap, in `time` CSR ticks, summed over all harts
+    pub fn idle_time(&self) -> usize {
+        self.harts.iter().filter_map(|hart| hart.idle.as_ref()).map(Idle::time).sum()
+    }
+
+    pub fn get_mut(&mut self, pid: usize) -> Option<&mut Process> {
+        self.processes.get_mut(&pid)
+    }
+}
+
+/// Start `proc` as a child of `parent`, 0 for the kernel. Returns its pid
+pub fn push(mut proc: Process, parent: usize) -> usize {
+    proc.parent = parent;
+    let mut sched = SCHEDULER.lock();
+    let pid = sched.next_pid.fetch_add(1, Ordering::Relaxed);
+    sched.processes.insert(pid, proc);
+    sched.make_ready(pid, Enqueue::New);
+    pid
+}
+
+/// Preempt the running process, or with `involuntary` unset, have it yield
+pub fn tick(tf: &mut TrapFrame, involuntary: bool) {
+    let mut sched = SCHEDULER.lock();
+    sched.tick(involuntary, tf);
+}
+
+/// Block the running process on its channels, unless a doorbell already arrived. A non-zero
+/// `deadline` bounds the wait
+pub fn wait_channel(tf: &mut TrapFrame, handle: usize, deadline: usize) {
+    let mut sched = SCHEDULER.lock();
+    let proc = sched.running_process();
+    if !proc.wait_armed(handle) {
+        return;
+    }
+    if deadline != 0 && deadline <= timer::rtc() {
+        tf.x[10] = SysError::TimedOut as isize as usize;
+        return;
+    }
+
+    let keys = proc
+        .handles
+        .channels()
+        .filter(|&(h, _)| handle == 0 || h == handle)
+        .map(|(_, ep)| ep.wait_key())
+        .collect();
+    sched.block_on(keys, Some(deadline).filter(|&deadline| deadline != 0), tf);
+}
+
+/// Switch to the other end of a channel right away, if it was just woken up by a doorbell
+pub fn handoff_channel(tf: &mut TrapFrame, handle: usize) {
+    let mut sched = SCHEDULER.lock();
+    let peer = match sched.running_process().handles.channel(handle, Rights::SIGNAL) {
+        Ok(ep) => ep.peer,
+        Err(_) => return,
+    };
+    // The kernel end of accept channels has no process
+    if peer != 0 {
+        sched.handoff(peer, tf);
+    }
+}
+
+/// Put the status of `pid` into a1, blocking until it exits. The caller holds a handle to it
+pub fn wait_exit(tf: &mut TrapFrame, pid: usize) {
+    let mut sched = SCHEDULER.lock();
+    if let Some(&status) = sched.exited.get(&pid) {
+        tf.x[11] = status.encode();
+    } else if sched.processes.contains_key(&pid) {
+        sched.block_on(vec![WaitKey::Exit(pid)], None, tf);
+    } else {
+        // Statuses are kept while handles exist, so only for pids that never were
+        tf.x[10] = SysError::NotFound as isize as usize;
+    }
+}
+
+/// Handle an IPI from another hart
+pub fn kick(tf: &mut TrapFrame) {
+    let mut sched = SCHEDULER.lock();
+    if !sched.kernel_request(tf) {
+        sched.kick(tf);
+    }
+}
+
+/// Kernel processes can't make syscalls. They leave a request and interrupt their own hart
+/// instead, which ends up in `kick` with their trap frame
+fn kernel_request(request: KernelRequest) {
+    unsafe { sstatus::clear_sie() };
+    SCHEDULER.lock().running_process().kernel_request = Some(request);
+    unsafe {
+        // Taken as soon as interrupts are back on, before any timer interrupt
+        core::arch::asm!("csrs sip, {}", in(reg) 1 << 1);
+        sstatus::set_sie();
+    }
+}
+
+/// Block the running kernel process on all of its channels, the way `wait_channel` blocks user
+/// processes. Sleeping flags are set and rechecked by the caller beforehand
+pub fn kernel_wait(deadline: usize) {
+    kernel_request(KernelRequest::Wait(deadline));
+}
+
+/// Let other ready processes run first, from a kernel process
+pub fn kernel_yield() {
+    kernel_request(KernelRequest::Yield);
+}
+
+/// Put the running process to sleep until `deadline`, in `time` CSR ticks
+pub fn sleep_until(tf: &mut TrapFrame, deadline: usize) {
+    if deadline <= timer::rtc() {
+        return;
+    }
+    let mut sched = SCHEDULER.lock();
+    sched.block_on(Vec::new(), Some(deadline), tf);
+}
+
+/// Print the counters of every process, see `Sched::dump`
+pub fn dump() {
+    let out = SCHEDULER.lock().dump();
+    uprint!("{}", out);
+}
+
+pub fn exit_running(tf: &mut TrapFrame, status: ExitStatus) {
+    let mut sched = SCHEDULER.lock();
+    sched.exit(status, tf);
+}
+
+/// Start scheduling on the current hart, which idles if nothing is ready
+pub fn bootstrap() {
+    let id = hart::id();
+    let mut sched = SCHEDULER.lock();
+    let idle = Idle::new(id);
+
+    // Reset stack
+    const TF_SIZE: usize = core::mem::size_of::<TrapFrame>();
+    let tf_push = (hart::stack_top(id) - TF_SIZE) as *mut TrapFrame;
+
+    unsafe {
+        tf_push.write(idle.tf.clone());
+        sched.harts[id].idle = Some(idle);
+        sched.switch_next(&mut *tf_push);
+        drop(sched);
+        mprintln!("[Sched] Bootstrap on {}", id);
+        kickoff_init(tf_push as usize);
+    }
+}
+
+unsafe fn kickoff_init(sp: usize) -> ! {
+    mprintln!(
+        "Jumping to process! new sp = {:#x}, kstack top = {:#x}",
+        sp,
+        sp + core::mem::size_of::<TrapFrame>()
+    );
+    core::arch::asm!(
+        "mv sp, a0",
+        "j trap_exit",
+        in("a0") sp,
+        options(noreturn),
+    )
+}
\ No newline at end of file
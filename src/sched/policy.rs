@@ -0,0 +1,82 @@
+//! Scheduling policies
+//!
+//! `Sched` does the mechanics: saving and loading trap frames, switching page tables, blocking
+//! and waking. Which ready process runs next, and for how long, is left to a policy. It only ever
+//! sees pids, and is told about every process that becomes ready or goes away.
+//!
+//! The best-effort policy is picked at build time: round-robin with the `sched-rr` feature, a
+//! multi-level feedback queue otherwise. Deadline processes are scheduled by `Edf` on top of it.
+
+use alloc::boxed::Box;
+
+use super::edf::Edf;
+use crate::syscall::SysError;
+
+/// Why a process is handed to the policy
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Enqueue {
+    /// Just spawned, or just stopped being a deadline process
+    New,
+    /// Was blocked
+    Woken,
+    /// Was running when its slice ran out
+    Preempted,
+    /// Was running and gave up the rest of its turn
+    Yielded,
+    /// Was running and made way for a particular process, without giving up its turn: a handoff,
+    /// or being moved off a hart dedicated to another process
+    HandedOff,
+}
+
+pub trait SchedPolicy: Send {
+    /// `pid` is ready to run
+    fn enqueue(&mut self, pid: usize, priority: usize, why: Enqueue);
+
+    /// Forget `pid`, queued or not, e.g. because it exited
+    fn dequeue(&mut self, pid: usize);
+
+    /// The base priority of `pid` changed
+    fn reprioritize(&mut self, pid: usize, priority: usize);
+
+    /// Take the process to run next off the queue, if any is ready
+    fn pick_next(&mut self) -> Option<usize>;
+
+    /// Take `pid` off the queue to run it right away, out of turn. Returns false if it is not
+    /// queued, e.g. because another hart picked it already
+    fn take(&mut self, pid: usize) -> bool;
+
+    /// The timer fired while `running` was on the CPU, None meaning idle.
+    /// Returns whether to preempt it
+    fn on_tick(&mut self, running: Option<usize>) -> bool;
+
+    /// Whether the hart running `running`, None meaning idle, has to keep its timer ticking to
+    /// preempt it. Harts go tickless otherwise, until something else is queued
+    fn wants_tick(&self, running: Option<usize>) -> bool;
+
+    /// Reserve `runtime` out of every `period` for `pid`, both in `time` CSR ticks, or drop its
+    /// reservation if `runtime` is 0. `ready` tells whether `pid` is queued right now
+    fn reserve(
+        &mut self,
+        _pid: usize,
+        _priority: usize,
+        _ready: bool,
+        _runtime: usize,
+        _period: usize,
+    ) -> Result<(), SysError> {
+        Err(SysError::Unsupported)
+    }
+}
+
+#[cfg(feature = "sched-rr")]
+fn best_effort() -> Box<dyn SchedPolicy> {
+    Box::new(super::rr::RoundRobin::default())
+}
+
+#[cfg(not(feature = "sched-rr"))]
+fn best_effort() -> Box<dyn SchedPolicy> {
+    Box::new(super::mlfq::Mlfq::default())
+}
+
+pub fn new() -> Box<dyn SchedPolicy> {
+    Box::new(Edf::new(best_effort()))
+}
@@ -0,0 +1,40 @@
+use alloc::collections::VecDeque;
+
+use super::policy::{Enqueue, SchedPolicy};
+
+/// Every process gets a slice in turn, priorities are ignored
+#[derive(Default)]
+pub struct RoundRobin {
+    ready: VecDeque<usize>,
+}
+
+impl SchedPolicy for RoundRobin {
+    fn enqueue(&mut self, pid: usize, _priority: usize, _why: Enqueue) {
+        self.ready.push_back(pid);
+    }
+
+    fn dequeue(&mut self, pid: usize) {
+        self.ready.retain(|&queued| queued != pid);
+    }
+
+    fn reprioritize(&mut self, _pid: usize, _priority: usize) {}
+
+    fn pick_next(&mut self) -> Option<usize> {
+        self.ready.pop_front()
+    }
+
+    fn take(&mut self, pid: usize) -> bool {
+        match self.ready.iter().position(|&queued| queued == pid) {
+            Some(idx) => self.ready.remove(idx).is_some(),
+            None => false,
+        }
+    }
+
+    fn on_tick(&mut self, _running: Option<usize>) -> bool {
+        true
+    }
+
+    fn wants_tick(&self, _running: Option<usize>) -> bool {
+        !self.ready.is_empty()
+    }
+}
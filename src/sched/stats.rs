@@ -0,0 +1,117 @@
+//! CPU accounting
+//!
+//! Each hart keeps a clock of the user and kernel time it spent since it last switched processes,
+//! updated on trap entry and exit without taking any lock. `Sched` adds it to the outgoing process
+//! on every switch. Idle time is accounted for separately, so what a hart spends idling is dropped.
+
+use crate::consts::MAX_HARTS;
+use crate::{hart, timer};
+
+/// Per-process counters. Times are in `time` CSR ticks
+#[derive(Default, Clone, Copy, Debug)]
+pub struct ProcStats {
+    pub user_time: usize,
+    /// Spent in traps on behalf of the process, or running it if it is a kernel process
+    pub kernel_time: usize,
+    /// Blocked, yielded or handed off
+    pub voluntary: usize,
+    /// Preempted
+    pub involuntary: usize,
+    pub wakeups: usize,
+    /// Between being woken up and running, summed over all wakeups
+    pub wakeup_latency: usize,
+    pub max_wakeup_latency: usize,
+    /// Woken up and not running yet
+    woken_at: Option<usize>,
+}
+
+impl ProcStats {
+    pub fn woken(&mut self, now: usize) {
+        self.woken_at = Some(now);
+    }
+
+    /// About to run
+    pub fn scheduled(&mut self, now: usize) {
+        if let Some(woken_at) = self.woken_at.take() {
+            let latency = now - woken_at;
+            self.wakeups += 1;
+            self.wakeup_latency += latency;
+            self.max_wakeup_latency = self.max_wakeup_latency.max(latency);
+        }
+    }
+}
+
+/// What the syscall copies out, see `user/channel.h`
+#[repr(C)]
+pub struct ProcInfo {
+    pub pid: usize,
+    /// `ProcState` as 0 for ready, 1 running, 2 blocked and 3 exited
+    pub state: usize,
+    pub priority: usize,
+    pub user_time: usize,
+    pub kernel_time: usize,
+    pub voluntary: usize,
+    pub involuntary: usize,
+    pub wakeups: usize,
+    pub wakeup_latency: usize,
+    pub max_wakeup_latency: usize,
+    /// 0 for the kernel
+    pub parent: usize,
+}
+
+impl ProcInfo {
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(self as *const _ as *const u8, core::mem::size_of::<Self>())
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Clock {
+    /// Last time the clock was updated
+    mark: usize,
+    user: usize,
+    kernel: usize,
+}
+
+/// Only ever touched by their own hart, with interrupts off
+static mut CLOCKS: [Clock; MAX_HARTS] = [Clock {
+    mark: 0,
+    user: 0,
+    kernel: 0,
+}; MAX_HARTS];
+
+fn clock() -> &'static mut Clock {
+    unsafe { &mut CLOCKS[hart::id()] }
+}
+
+/// The hart trapped, from user mode or from a kernel process
+pub fn trap_enter(from_user: bool) {
+    let clock = clock();
+    let now = timer::rtc();
+    if from_user {
+        clock.user += now - clock.mark;
+    } else {
+        clock.kernel += now - clock.mark;
+    }
+    clock.mark = now;
+}
+
+/// The hart is about to return from a trap
+pub fn trap_exit() {
+    let clock = clock();
+    let now = timer::rtc();
+    clock.kernel += now - clock.mark;
+    clock.mark = now;
+}
+
+/// User and kernel time since the last call, to be charged to the process that was running
+pub fn take() -> (usize, usize) {
+    trap_exit();
+    let clock = clock();
+    let taken = (clock.user, clock.kernel);
+    clock.user = 0;
+    clock.kernel = 0;
+    taken
+}
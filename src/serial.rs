@@ -88,6 +88,21 @@ impl UART16550 {
             core::ptr::read_volatile((self.base + (offsets::RBR << self.shift)) as *const u8)
         }
     }
+
+    pub fn try_getchar(&self) -> Option<u8> {
+        unsafe {
+            if core::ptr::read_volatile((self.base + (offsets::LSR << self.shift)) as *const u8)
+                & masks::DR
+                == 0
+            {
+                return None;
+            }
+
+            Some(core::ptr::read_volatile(
+                (self.base + (offsets::RBR << self.shift)) as *const u8,
+            ))
+        }
+    }
 }
 
 static mut SERIAL: UART16550 = UART16550::new(0x10000000, 0, 11_059_200, 115200);
@@ -98,6 +113,10 @@ pub fn putc(c: u8) {
     }
 }
 
+pub fn try_getc() -> Option<u8> {
+    unsafe { SERIAL.try_getchar() }
+}
+
 pub fn print(s: &str) {
     for c in s.as_bytes() {
         putc(*c);
@@ -126,6 +145,12 @@ macro_rules! uprint {
     });
 }
 
+#[macro_export]
+macro_rules! uprintln {
+    () => ($crate::uprint!("\n"));
+    ($($arg:tt)*) => ($crate::uprint!("{}\n", format_args!($($arg)*)));
+}
+
 #[macro_export]
 macro_rules! mprint {
     ($($arg:tt)*) => ({
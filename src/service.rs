@@ -1,89 +1,375 @@
-use core::{sync::atomic::{AtomicU8, Ordering, AtomicU16, AtomicBool, AtomicU32}};
+use core::sync::atomic::Ordering;
 
-use crate::{uprint, mem::{Frame, addr::{PhysAddr, VirtAddr}, set::{MapArea, MapPermission}}, process::{Process, UserCaps}, mprintln, prog};
+use alloc::{collections::BTreeMap, string::String, sync::Arc};
+use lazy_static::lazy_static;
+use spin::Mutex;
 
-#[repr(C)]
-pub struct PutcharQueue {
-    pub recv: AtomicU32,
-    pub trans: AtomicU32,
-    pub remote_sleeping: AtomicBool,
-    pub closed: AtomicBool,
+use riscv::register::sstatus;
 
-    pub data: [(u64, u64); 255],
+use crate::{uprint, uprintln, mem::{addr::PhysPageNum, OutOfMemory, SharedFrames}, process::{handle::{KObject, Rights}, Process, UserCaps}, mprintln, prog, channel::{op, Channel, Cqe, Sqe, Accept, AcceptChannel, Side}, sched::{self, WaitKey}, serial, syscall::{self, SysError}, timer};
+
+/// Console service. Operations complete in order, except `op::TIMEOUT` which completes when due
+pub type ConsoleChannel = Channel<Sqe, Cqe, 64>;
+
+const KSERVICE_MAX_CLIENTS: usize = 64;
+const KSERVICE_MAX_TIMEOUTS: usize = 64;
+
+#[derive(Clone, Copy)]
+struct KClient {
+    chan: &'static ConsoleChannel,
+    /// Our handles to the channel and to the client process
+    handle: usize,
+    process: usize,
 }
 
-fn putchar_kservice(queue: &mut PutcharQueue) -> ! {
-    if core::mem::size_of::<PutcharQueue>() != 0x1000 {
-        panic!("Incorrect putchar queue size: {}", core::mem::size_of::<PutcharQueue>());
+impl KClient {
+    /// Doorbell towards the client. Kernel services are preemptible and the timer handler takes
+    /// the scheduler lock too, so it is taken with interrupts masked. The client is looked up
+    /// through our endpoint, since it changes when the channel is moved
+    fn notify(&self) {
+        if self.chan.header.sleeping(Side::Client).swap(false, Ordering::SeqCst) {
+            unsafe { sstatus::clear_sie() };
+            let mut sch = sched::SCHEDULER.lock();
+            if let Ok(ep) = sch.running_process().handles.channel(self.handle, Rights::SIGNAL) {
+                let pid = ep.peer;
+                sch.wake(pid);
+            }
+            drop(sch);
+            unsafe { sstatus::set_sie() };
+        }
     }
 
+    /// Release our end once the client is gone
+    fn release(&self) {
+        kservice_close(&[self.handle, self.process]);
+    }
+}
+
+/// Close handles of the running kernel service. Masked like `KClient::notify`, which also keeps
+/// the heap lock from being held across a preemption
+fn kservice_close(handles: &[usize]) {
+    unsafe { sstatus::clear_sie() };
+    for &handle in handles {
+        syscall::close(handle).unwrap();
+    }
+    unsafe { sstatus::set_sie() };
+}
+
+#[derive(Clone, Copy)]
+struct PendingTimeout {
+    client: KClient,
+    user_data: u64,
+    deadline: usize,
+}
+
+fn console_kservice(accept: &AcceptChannel) -> ! {
+    if !accept.is_valid() {
+        panic!("Incorrect accept channel header at {:#x}", accept as *const _ as usize);
+    }
+
+    // No allocation in here outside `kservice_close`: the heap lock may be held across a preemption
+    let mut clients: [Option<KClient>; KSERVICE_MAX_CLIENTS] = [None; KSERVICE_MAX_CLIENTS];
+    let mut client_cnt = 0;
+    let mut timeouts: [Option<PendingTimeout>; KSERVICE_MAX_TIMEOUTS] = [None; KSERVICE_MAX_TIMEOUTS];
+
     loop {
-        let cur = queue.recv.load(Ordering::Relaxed);
-        mprintln!("Tx {}, Rx {}", queue.trans.load(Ordering::Relaxed), queue.recv.load(Ordering::Relaxed));
-        if queue.trans.load(Ordering::Acquire) > cur {
-            let (_, c) = queue.data[cur as usize % 255];
-            queue.recv.fetch_add(1, Ordering::Relaxed);
-            uprint!("{}", c as u8 as char);
+        let mut progress = false;
+        while let Some(conn) = accept.recv() {
+            progress = true;
+            if client_cnt == KSERVICE_MAX_CLIENTS {
+                // The client sees its channel closed
+                mprintln!("[kconsole] dropping client {}", conn.pid);
+                kservice_close(&[conn.handle as usize, conn.process as usize]);
+                continue;
+            }
+            clients[client_cnt] = Some(KClient {
+                chan: unsafe { &*(conn.base as *const ConsoleChannel) },
+                handle: conn.handle as usize,
+                process: conn.process as usize,
+            });
+            client_cnt += 1;
         }
+
+        // Client is gone, drop its timeouts and release our end
+        let mut idx = 0;
+        while idx < client_cnt {
+            let client = clients[idx].unwrap();
+            if !client.chan.is_closed() {
+                idx += 1;
+                continue;
+            }
+
+            for slot in timeouts.iter_mut() {
+                if slot.map_or(false, |t| core::ptr::eq(t.client.chan, client.chan)) {
+                    *slot = None;
+                }
+            }
+            client.release();
+            progress = true;
+            client_cnt -= 1;
+            clients[idx] = clients[client_cnt].take();
+        }
+
+        let now = timer::rtc();
+        for slot in timeouts.iter_mut() {
+            if let Some(t) = slot {
+                if t.deadline <= now && t.client.chan.can_reply() {
+                    t.client.chan.reply(Cqe { user_data: t.user_data, res: 0, value: 0 }).unwrap();
+                    t.client.notify();
+                    *slot = None;
+                    progress = true;
+                }
+            }
+        }
+
+        for &client in clients[..client_cnt].iter().flatten() {
+            let chan = client.chan;
+            let mut drained = false;
+            // Leave entries queued until there is room for their completion
+            while chan.can_reply() {
+                let sqe = match chan.recv() {
+                    Some(sqe) => sqe,
+                    None => break,
+                };
+                drained = true;
+
+                let (res, value) = match sqe.opcode {
+                    op::NOP => (0, 0),
+                    op::WRITE if sqe.len as usize <= Sqe::INLINE_MAX => {
+                        for i in 0..sqe.len as usize {
+                            uprint!("{}", sqe.payload(i) as char);
+                        }
+                        (sqe.len as i64, 0)
+                    }
+                    op::READ if sqe.len <= 8 => {
+                        let mut cnt = 0;
+                        let mut value = 0;
+                        while cnt < sqe.len {
+                            match serial::try_getc() {
+                                Some(c) => value |= (c as u64) << (cnt * 8),
+                                None => break,
+                            }
+                            cnt += 1;
+                        }
+                        (cnt as i64, value)
+                    }
+                    op::TIMEOUT if sqe.wants_cqe() => {
+                        match timeouts.iter_mut().find(|slot| slot.is_none()) {
+                            Some(slot) => {
+                                *slot = Some(PendingTimeout {
+                                    client,
+                                    user_data: sqe.user_data,
+                                    deadline: now.saturating_add(sqe.args[0] as usize),
+                                });
+                                continue;
+                            }
+                            None => (SysError::Busy as i64, 0),
+                        }
+                    }
+                    op::TIMEOUT => continue,
+                    op::WRITE | op::READ => (SysError::InvalidArgument as i64, 0),
+                    // A bogus buffer address from a client would fault the kernel
+                    op::WRITE_BUF => (SysError::Unsupported as i64, 0),
+                    _ => (SysError::Unsupported as i64, 0),
+                };
+
+                if sqe.wants_cqe() {
+                    chan.reply(Cqe { user_data: sqe.user_data, res, value }).unwrap();
+                }
+            }
+
+            // The client may be waiting for ring space or a completion
+            if drained {
+                client.notify();
+            }
+            progress |= drained;
+        }
+
+        if progress {
+            continue;
+        }
+
+        let deadline = timeouts.iter().flatten().map(|t| t.deadline).min();
+        // Due already, but the client has no room for the completion yet
+        if deadline.map_or(false, |deadline| deadline <= timer::rtc()) {
+            sched::kernel_yield();
+            continue;
+        }
+
+        // Idle: arm every channel, recheck, and sleep until some client rings or a timeout is due
+        let chans = || clients[..client_cnt].iter().flatten().map(|client| client.chan);
+        accept.header.sleeping(Side::Server).store(true, Ordering::SeqCst);
+        chans().for_each(|chan| chan.header.sleeping(Side::Server).store(true, Ordering::SeqCst));
+        if !accept.has_request() && !chans().any(|chan| chan.has_request()) {
+            sched::kernel_wait(deadline.unwrap_or(0));
+        }
+        accept.header.sleeping(Side::Server).store(false, Ordering::SeqCst);
+        chans().for_each(|chan| chan.header.sleeping(Side::Server).store(false, Ordering::SeqCst));
     }
 }
 
-fn putchar_kboot() -> (PhysAddr, PhysAddr) {
-    let req = Frame::alloc();
-    let resp = Frame::alloc();
-    
-    let req_paddr: PhysAddr = req.ppn().into();
-    let resp_paddr: PhysAddr = resp.ppn().into();
+fn console_kspawn() -> Result<Process, OutOfMemory> {
+    Process::new_kernel(console_kservice as usize, [0, 0])
+}
+
+/// The serial port is handed over as an MMIO handle in a2. It connects to nothing
+fn console_uspawn() -> Result<Process, OutOfMemory> {
+    let caps = UserCaps {
+        reserve: true,
+        pin_hart: true,
+        ..Default::default()
+    };
+    let mut proc = Process::new_user(prog::CONSOLE, [0, 0], caps)?;
+    let serial = PhysPageNum(0x10000)..PhysPageNum(0x10001);
+    proc.tf.x[12] = proc.handles.insert(KObject::Mmio(serial), Rights::MMIO);
+    Ok(proc)
+}
 
-    // Zeroing pages
-    unsafe { *(req_paddr.0 as *mut [u8; 4096]) = [0u8; 4096] };
-    unsafe { *(resp_paddr.0 as *mut [u8; 4096]) = [0u8; 4096] };
+pub const MAX_NAME_LEN: usize = 64;
 
-    let kservice = Process::new_kernel(putchar_kservice as usize, [req_paddr.0, resp_paddr.0]);
-    crate::sched::push(kservice);
+/// Creates the service process. It receives its accept channel in a0, and the handle to it in a1.
+/// Its parent is the client whose connect started it
+pub type ServiceSpawn = fn() -> Result<Process, OutOfMemory>;
 
-    // TODO: track frame in processes
-    core::mem::forget(req);
-    core::mem::forget(resp);
+/// Allocates frames for one client channel
+pub type ChannelAlloc = fn() -> Result<Arc<SharedFrames>, OutOfMemory>;
 
-    (req_paddr, resp_paddr)
+pub struct ServiceEntry {
+    pub version: u32,
+    pub spawn: ServiceSpawn,
+    pub channel: ChannelAlloc,
+    instance: Option<Instance>,
 }
 
-fn putchar_uboot() -> (PhysAddr, PhysAddr) {
-    let req = Frame::alloc();
-    let resp = Frame::alloc();
-    
-    let req_paddr: PhysAddr = req.ppn().into();
-    let resp_paddr: PhysAddr = resp.ppn().into();
-
-    // Zeroing pages
-    unsafe { *(req_paddr.0 as *mut [u8; 4096]) = [0u8; 4096] };
-    unsafe { *(resp_paddr.0 as *mut [u8; 4096]) = [0u8; 4096] };
-
-    let mut uservice = Process::new_user(prog::PUTCHAR, [0x64000000, 0x10000000], UserCaps { serial: true });
-
-    let req_area = MapArea::linear(
-        req_paddr.floor()..PhysAddr(req_paddr.0 + 1).ceil(),
-        VirtAddr(0x64000000).into(),
-        MapPermission::U | MapPermission::W | MapPermission::R,
-    );
-    let resp_area = MapArea::linear(
-        resp_paddr.floor()..PhysAddr(resp_paddr.0 + 1).ceil(),
-        VirtAddr(0x64001000).into(),
-        MapPermission::U | MapPermission::W | MapPermission::R,
-    );
-    uservice.mset.push(req_area, None);
-    uservice.mset.push(resp_area, None);
-
-    crate::sched::push(uservice);
-
-    // TODO: track frame in processes
-    core::mem::forget(req);
-    core::mem::forget(resp);
-
-    (req_paddr, resp_paddr)
+/// A running service process
+struct Instance {
+    pid: usize,
+    accept: Arc<SharedFrames>,
 }
 
-pub const SERVICE_LIST: [fn() -> (PhysAddr, PhysAddr); 1] = [
-    putchar_uboot,
-];
\ No newline at end of file
+impl Instance {
+    fn start(spawn: ServiceSpawn, parent: usize) -> Result<Self, SysError> {
+        let accept = AcceptChannel::alloc()?;
+
+        let mut proc = spawn()?;
+        // Clients wait on services, so they go first
+        proc.priority = 0;
+        let (handle, base) = proc.map_channel(&accept, Side::Server, 0, Rights::CHANNEL)?;
+        proc.tf.x[10] = base;
+        proc.tf.x[11] = handle;
+        let pid = sched::push(proc, parent);
+        mprintln!("[Service] started as pid {}", pid);
+
+        Ok(Self { pid, accept })
+    }
+
+    fn accept_channel(&self) -> &AcceptChannel {
+        unsafe { AcceptChannel::from_phys(self.accept.ppn(0)) }
+    }
+
+    /// The accept channel is closed when the service process exits
+    fn is_dead(&self) -> bool {
+        self.accept_channel().is_closed()
+    }
+}
+
+lazy_static! {
+    pub static ref REGISTRY: Mutex<BTreeMap<String, ServiceEntry>> = Mutex::new(BTreeMap::new());
+}
+
+pub fn register(name: &str, version: u32, spawn: ServiceSpawn, channel: ChannelAlloc) {
+    assert!(name.len() <= MAX_NAME_LEN, "Service name too long: {}", name);
+    mprintln!("[Service] registering {} v{}", name, version);
+    let prev = REGISTRY.lock().insert(name.into(), ServiceEntry { version, spawn, channel, instance: None });
+    assert!(prev.is_none(), "Service {} registered twice", name);
+}
+
+/// Connect the running process to a service providing at least the given version.
+/// The service is started on first use. Returns the channel handle of the client and where the
+/// channel is mapped there
+/// Returns the channel handle and base, and a handle to the service process if this connect
+/// started it, 0 otherwise
+pub fn connect(name: &[u8], version: u32) -> Result<(usize, usize, usize), SysError> {
+    let name = core::str::from_utf8(name).map_err(|_| SysError::InvalidArgument)?;
+
+    // Checked before the lookup, so denied processes can't probe for service names either
+    let (pid, caps) = {
+        let mut sch = sched::SCHEDULER.lock();
+        (sch.running_pid(), sch.running_process().caps)
+    };
+    if !caps.may_connect(name) {
+        uprintln!("[Service] denied: process {} may not connect to {}", pid, name);
+        return Err(SysError::Denied);
+    }
+
+    let mut registry = REGISTRY.lock();
+    let entry = registry.get_mut(name).ok_or(SysError::NotFound)?;
+    if entry.version < version {
+        return Err(SysError::VersionMismatch);
+    }
+
+    let started = entry.instance.as_ref().map_or(true, Instance::is_dead);
+    if started {
+        entry.instance = Some(Instance::start(entry.spawn, pid)?);
+    }
+    let instance = entry.instance.as_ref().unwrap();
+    let accept = instance.accept_channel();
+    if !accept.can_send() {
+        return Err(SysError::Busy);
+    }
+
+    let frames = (entry.channel)()?;
+
+    let mut sch = sched::SCHEDULER.lock();
+    let client = sch.running_pid();
+    let client_chan = sch.running_process().map_channel(&frames, Side::Client, instance.pid, Rights::CHANNEL)?;
+    let service = match sch.get_mut(instance.pid) {
+        Some(service) => service,
+        None => {
+            // Exited on another hart since the check above. The next connect restarts it
+            sch.running_process().unmap_channel(client_chan.0).unwrap();
+            return Err(SysError::Closed);
+        }
+    };
+    let (service_handle, service_base) = match service.map_channel(&frames, Side::Server, client, Rights::CHANNEL) {
+        Ok(mapped) => mapped,
+        Err(err) => {
+            // The service never heard of it, so there is no one to tell
+            sch.running_process().unmap_channel(client_chan.0).unwrap();
+            return Err(err);
+        }
+    };
+    let process = service.handles.insert(KObject::Process(client), Rights::PROCESS);
+
+    mprintln!("[Service] {} accepting client {}", name, client);
+    accept
+        .send(Accept {
+            base: service_base as u64,
+            handle: service_handle as u64,
+            process: process as u64,
+            pid: client as u64,
+        })
+        .unwrap();
+
+    // The kernel is the client of accept channels, so it also rings their doorbell
+    if accept.header.sleeping(Side::Server).swap(false, Ordering::SeqCst) {
+        sch.wake_queue(WaitKey::Channel(instance.accept.ppn(0).0));
+    }
+    // Without it, a connect failing above leaves the service to the kernel once it exits
+    let child = if started {
+        sch.running_process().handles.insert(KObject::Process(instance.pid), Rights::PROCESS)
+    } else {
+        0
+    };
+    drop(sch);
+
+    unsafe {
+        riscv::asm::sfence_vma_all();
+    }
+    Ok((client_chan.0, client_chan.1, child))
+}
+
+pub fn init() {
+    register("console", 1, console_uspawn, ConsoleChannel::alloc);
+    register("kconsole", 1, console_kspawn, ConsoleChannel::alloc);
+}
@@ -0,0 +1,516 @@
+use crate::consts::PAGE_SIZE;
+use crate::mem::addr::VirtAddr;
+use crate::mem::set::MapPermission;
+use crate::mem::{Frame, OutOfMemory, SharedFrames};
+use crate::process::handle::{KObject, Rights};
+use crate::process::{Grant, Process};
+use crate::sched::ExitStatus;
+use crate::trap::TrapFrame;
+use crate::{hart, mem, mprintln, sched, service, timer, uprint};
+
+// Syscall numbers, passed in a0. Arguments follow in a1..
+pub const SYS_CONNECT: usize = 0x3;
+pub const SYS_WAIT: usize = 0x4;
+pub const SYS_DOORBELL: usize = 0x5;
+pub const SYS_CLOSE: usize = 0x6;
+pub const SYS_GRANT: usize = 0x7;
+pub const SYS_REVOKE: usize = 0x8;
+pub const SYS_DUP: usize = 0x9;
+pub const SYS_TRANSFER: usize = 0xA;
+pub const SYS_MAP: usize = 0xB;
+pub const SYS_UNMAP: usize = 0xC;
+pub const SYS_MEM_CREATE: usize = 0xD;
+pub const SYS_EXIT: usize = 0xE;
+pub const SYS_PROCESS_WAIT: usize = 0xF;
+pub const SYS_YIELD: usize = 0x10;
+pub const SYS_SET_PRIORITY: usize = 0x11;
+pub const SYS_RESERVE: usize = 0x12;
+pub const SYS_PIN_HART: usize = 0x13;
+pub const SYS_SLEEP: usize = 0x14;
+pub const SYS_PROC_INFO: usize = 0x15;
+pub const SYS_PROC_DUMP: usize = 0x16;
+pub const SYS_LENT: usize = 0x17;
+pub const SYS_PUTCHAR: usize = 0x100;
+
+/// Doorbell flag: switch to the peer right away if it was sleeping, giving it the rest of the
+/// caller's slice
+pub const DOORBELL_HANDOFF: usize = 1 << 0;
+
+/// Pin argument: any hart that is free
+pub const PIN_ANY: usize = usize::MAX;
+/// Pin argument: give the hart back
+pub const PIN_NONE: usize = usize::MAX - 1;
+
+/// Error codes. Like SBI calls, a0 holds 0 or one of these on return, and a1 holds the value
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(isize)]
+pub enum SysError {
+    InvalidSyscall = -1,
+    InvalidArgument = -2,
+    NotFound = -3,
+    VersionMismatch = -4,
+    Busy = -5,
+    Closed = -6,
+    Unsupported = -7,
+    /// The handle lacks a right the operation needs
+    Denied = -8,
+    /// The deadline passed before anything woke the caller up
+    TimedOut = -9,
+    /// Ran out of physical frames
+    NoMemory = -10,
+}
+
+impl From<OutOfMemory> for SysError {
+    fn from(_: OutOfMemory) -> Self {
+        SysError::NoMemory
+    }
+}
+
+pub type SysResult = Result<usize, SysError>;
+
+pub fn syscall(tf: &mut TrapFrame) {
+    mprintln!("[SyncSyscall] num: {}", tf.x[10]);
+    let (nr, arg) = (tf.x[10], tf.x[11]);
+    let result = match nr {
+        SYS_CONNECT => connect(tf.x[11], tf.x[12], tf.x[13]).map(|(handle, base, child)| {
+            tf.x[12] = base;
+            tf.x[13] = child;
+            handle
+        }),
+        SYS_WAIT => check_wait(tf.x[11]),
+        SYS_DOORBELL => doorbell(tf.x[11], tf.x[12]),
+        SYS_CLOSE => close(tf.x[11]),
+        SYS_GRANT => grant(tf.x[11], tf.x[12], tf.x[13], tf.x[14]),
+        SYS_REVOKE => revoke(tf.x[11], tf.x[12]),
+        SYS_LENT => lent(tf.x[11], tf.x[12], tf.x[13]),
+        SYS_DUP => dup(tf.x[11], tf.x[12]),
+        SYS_TRANSFER => transfer(tf.x[11], tf.x[12], tf.x[13]),
+        SYS_MAP => map(tf.x[11]),
+        SYS_UNMAP => unmap(tf.x[11]),
+        SYS_MEM_CREATE => mem_create(tf.x[11]),
+        SYS_EXIT => Ok(0),
+        SYS_YIELD => Ok(0),
+        SYS_SET_PRIORITY => set_priority(tf.x[11], tf.x[12]),
+        SYS_RESERVE => reserve(tf.x[11], tf.x[12]),
+        SYS_PIN_HART => pin_hart(tf.x[11]),
+        SYS_SLEEP => Ok(0),
+        SYS_PROC_INFO => proc_info(tf.x[11], tf.x[12], tf.x[13]),
+        SYS_PROC_DUMP => proc_dump(),
+        SYS_PROCESS_WAIT => process_wait(tf.x[11]),
+        SYS_PUTCHAR => {
+            // Sync call putchar
+            uprint!("{}", tf.x[11] as u8 as char);
+            Ok(0)
+        }
+        _ => Err(SysError::InvalidSyscall),
+    };
+
+    match result {
+        Ok(val) => {
+            tf.x[10] = 0;
+            tf.x[11] = val;
+        }
+        Err(e) => {
+            mprintln!("[SyncSyscall] failed: {:?}", e);
+            tf.x[10] = e as isize as usize;
+        }
+    }
+
+    tf.sepc += 4;
+
+    // Blocking switches tf to another process, so it has to come after the return values are set
+    match result {
+        Ok(_) if nr == SYS_WAIT => {
+            let deadline = tf.x[12];
+            sched::wait_channel(tf, arg, deadline)
+        }
+        Ok(1) if nr == SYS_DOORBELL && tf.x[12] & DOORBELL_HANDOFF != 0 => sched::handoff_channel(tf, arg),
+        Ok(pid) if nr == SYS_PROCESS_WAIT => sched::wait_exit(tf, pid),
+        _ if nr == SYS_EXIT => sched::exit_running(tf, ExitStatus::Exited(arg as u32)),
+        _ if nr == SYS_YIELD => sched::tick(tf, false),
+        _ if nr == SYS_SLEEP => sched::sleep_until(tf, arg),
+        // Move to the hart, or off it
+        Ok(_) if nr == SYS_PIN_HART => sched::tick(tf, false),
+        _ => {}
+    }
+}
+
+/// a1: name ptr, a2: name len, a3: minimum version.
+/// Returns the channel handle, and where the channel is mapped in a2
+fn connect(name_ptr: usize, name_len: usize, version: usize) -> Result<(usize, usize, usize), SysError> {
+    if name_len > service::MAX_NAME_LEN {
+        return Err(SysError::InvalidArgument);
+    }
+
+    let name = sched::SCHEDULER
+        .lock()
+        .running_process()
+        .mset
+        .read_user(VirtAddr(name_ptr), name_len)
+        .ok_or(SysError::InvalidArgument)?;
+    mprintln!("[SyncSyscall] connecting to {:?}", core::str::from_utf8(&name));
+    service::connect(&name, version as u32)
+}
+
+/// a1: channel handle, or 0 for every channel of the process, a2: deadline in ticks of the
+/// `time` CSR, or 0 to wait for as long as it takes.
+/// The caller sets its sleeping flag on the channels and rechecks them before waiting
+fn check_wait(handle: usize) -> SysResult {
+    let mut sch = sched::SCHEDULER.lock();
+    let handles = &sch.running_process().handles;
+    if handle == 0 {
+        if handles.channels().next().is_none() {
+            return Err(SysError::InvalidArgument);
+        }
+    } else {
+        handles.channel(handle, Rights::WAIT)?;
+    }
+    Ok(0)
+}
+
+/// a1: channel handle, a2: `DOORBELL_*` flags. Returns 1 if the peer was sleeping and has been
+/// woken up
+fn doorbell(handle: usize, flags: usize) -> SysResult {
+    if flags & !DOORBELL_HANDOFF != 0 {
+        return Err(SysError::InvalidArgument);
+    }
+
+    let mut sch = sched::SCHEDULER.lock();
+    let ep = sch
+        .running_process()
+        .handles
+        .channel(handle, Rights::SIGNAL)?;
+
+    if !ep.notify_peer() {
+        return Ok(0);
+    }
+
+    let key = ep.wait_key();
+    sch.wake_queue(key);
+    Ok(1)
+}
+
+/// a1: handle. Closing a channel unmaps it and marks it closed for the other end
+pub fn close(handle: usize) -> SysResult {
+    let mut sch = sched::SCHEDULER.lock();
+    let proc = sch.running_process();
+    if proc.handles.channel(handle, Rights::empty()).is_err() {
+        let entry = proc.handles.remove(handle).ok_or(SysError::InvalidArgument)?;
+        if let KObject::Process(pid) = entry.object {
+            sch.release_exited(pid);
+        }
+        return Ok(0);
+    }
+
+    let ep = proc.unmap_channel(handle)?;
+    sch.close_endpoint(ep);
+
+    unsafe {
+        riscv::asm::sfence_vma_all();
+    }
+    Ok(0)
+}
+
+/// a1: channel handle, a2: first page, a3: page count, a4: `MapPermission` bits out of R, W and X.
+/// Lends the pages to the other end of the channel, returning where they are mapped there.
+/// The address is meaningless to the caller; it is meant to be sent over the channel
+fn grant(handle: usize, start: usize, pages: usize, perm: usize) -> SysResult {
+    let perm = MapPermission::from_bits(perm as u8)
+        .filter(|perm| !perm.is_empty() && !perm.contains(MapPermission::U))
+        .ok_or(SysError::InvalidArgument)?;
+    if start % PAGE_SIZE != 0 || pages == 0 {
+        return Err(SysError::InvalidArgument);
+    }
+
+    let mut sch = sched::SCHEDULER.lock();
+    let proc = sch.running_process();
+    let ep = proc.handles.channel(handle, Rights::SIGNAL)?;
+    let (peer, channel) = (ep.peer, ep.frames.ppn(0).0);
+    if peer == 0 {
+        // The kernel end of accept channels takes no grants
+        return Err(SysError::InvalidArgument);
+    }
+    let ppns = proc
+        .mset
+        .owned_ppns(VirtAddr(start).into(), pages, perm)
+        .ok_or(SysError::InvalidArgument)?;
+
+    let peer_base = sch
+        .get_mut(peer)
+        .ok_or(SysError::Closed)?
+        .map_grant(&ppns, perm)?;
+    mprintln!("[SyncSyscall] granted {} pages at {:#x} to {} at {:#x}", pages, start, peer, peer_base);
+    sch.running_process().grants.push(Grant {
+        peer,
+        channel,
+        perm,
+        peer_base,
+        pages,
+    });
+    Ok(peer_base)
+}
+
+/// a1: channel handle, a2: address, a3: length. Succeeds if the peer lent all of it to the caller
+/// through this channel for reading, fails with `InvalidArgument` otherwise. Services check
+/// buffers clients point them at before touching them
+fn lent(handle: usize, addr: usize, len: usize) -> SysResult {
+    let end = addr.checked_add(len).ok_or(SysError::InvalidArgument)?;
+
+    let mut sch = sched::SCHEDULER.lock();
+    let pid = sch.running_pid();
+    let ep = sch.running_process().handles.channel(handle, Rights::READ)?;
+    let (peer, channel) = (ep.peer, ep.frames.ppn(0).0);
+    if peer == 0 {
+        return Err(SysError::InvalidArgument);
+    }
+    let lender = sch.get_mut(peer).ok_or(SysError::Closed)?;
+    let covered = lender.grants.iter().any(|grant| {
+        grant.peer == pid
+            && grant.channel == channel
+            && grant.perm.contains(MapPermission::R)
+            && grant.peer_base <= addr
+            && end <= grant.peer_base + grant.pages * PAGE_SIZE
+    });
+    if covered {
+        Ok(0)
+    } else {
+        Err(SysError::InvalidArgument)
+    }
+}
+
+/// a1: channel handle, a2: address returned by grant. Unmaps the pages from the peer
+fn revoke(handle: usize, peer_base: usize) -> SysResult {
+    let mut sch = sched::SCHEDULER.lock();
+    let proc = sch.running_process();
+    let peer = proc.handles.channel(handle, Rights::SIGNAL)?.peer;
+    let idx = proc
+        .grants
+        .iter()
+        .position(|grant| grant.peer == peer && grant.peer_base == peer_base)
+        .ok_or(SysError::InvalidArgument)?;
+    let grant = proc.grants.remove(idx);
+    sch.revoke(grant);
+
+    // The peer may be running on another hart
+    hart::shootdown();
+    Ok(0)
+}
+
+/// a1: handle, a2: `Rights` bits for the copy, at most those of the original
+fn dup(handle: usize, rights: usize) -> SysResult {
+    let rights = Rights::from_bits(rights as u32).ok_or(SysError::InvalidArgument)?;
+    sched::SCHEDULER
+        .lock()
+        .running_process()
+        .handles
+        .duplicate(handle, rights)
+}
+
+/// a1: channel handle, a2: handle to move, a3: `Rights` bits it keeps.
+/// Moves the handle into the other end of the channel, returning its number there.
+/// Like a grant, the number is meant to be sent over the channel
+fn transfer(chan: usize, handle: usize, rights: usize) -> SysResult {
+    let rights = Rights::from_bits(rights as u32).ok_or(SysError::InvalidArgument)?;
+    if handle == chan {
+        return Err(SysError::InvalidArgument);
+    }
+
+    let mut sch = sched::SCHEDULER.lock();
+    let handles = &mut sch.running_process().handles;
+    let peer = handles.channel(chan, Rights::WRITE)?.peer;
+    let entry = handles.get(handle, Rights::TRANSFER)?;
+    if !entry.rights.contains(rights) {
+        return Err(SysError::Denied);
+    }
+    if peer == 0 || sch.get_mut(peer).is_none() {
+        return Err(SysError::Closed);
+    }
+
+    if sch.running_process().handles.channel(handle, Rights::empty()).is_ok() {
+        return transfer_endpoint(&mut sch, handle, peer, rights);
+    }
+    let object = sch.running_process().handles.remove(handle).unwrap().object;
+    Ok(sch.get_mut(peer).unwrap().handles.insert(object, rights))
+}
+
+/// Move a channel endpoint of the running process to `to`: map the channel there, unmap it here,
+/// and point the other end at its new owner. Fails with `Busy` while pages are lent through it,
+/// since they are mapped in the old owner
+fn transfer_endpoint(sch: &mut sched::Sched, handle: usize, to: usize, rights: Rights) -> SysResult {
+    let ep = sch.running_process().handles.channel(handle, Rights::empty())?;
+    let (frames, side, other) = (ep.frames.clone(), ep.side, ep.peer);
+    let channel = frames.ppn(0).0;
+    if other == 0 {
+        // Accept channels, whose kernel end knows the service by the pid it started
+        return Err(SysError::InvalidArgument);
+    }
+    let lent = |proc: &Process| proc.grants.iter().any(|grant| grant.channel == channel);
+    if lent(sch.running_process()) || sch.get_mut(other).map_or(false, |proc| lent(proc)) {
+        return Err(SysError::Busy);
+    }
+
+    let (moved, _) = sch.get_mut(to).unwrap().map_channel(&frames, side, other, rights)?;
+    sch.running_process().unmap_channel(handle).unwrap();
+    // Gone already if the channel is closed
+    if let Some(proc) = sch.get_mut(other) {
+        proc.handles
+            .channels_mut()
+            .filter(|ep| ep.frames.ppn(0).0 == channel && ep.side == side.peer())
+            .for_each(|ep| ep.peer = to);
+    }
+
+    unsafe {
+        riscv::asm::sfence_vma_all();
+    }
+    Ok(moved)
+}
+
+/// a1: memory or MMIO handle. Returns where it is mapped
+fn map(handle: usize) -> SysResult {
+    sched::SCHEDULER.lock().running_process().map_object(handle)
+}
+
+/// a1: address returned by map
+fn unmap(addr: usize) -> SysResult {
+    sched::SCHEDULER
+        .lock()
+        .running_process()
+        .unmap_object(addr)?;
+
+    unsafe {
+        riscv::asm::sfence_vma_all();
+    }
+    Ok(0)
+}
+
+/// Upper bound for a single memory object
+const MEM_CREATE_MAX_PAGES: usize = 1024;
+
+/// a1: page count. Returns a handle to fresh zeroed memory
+fn mem_create(pages: usize) -> SysResult {
+    if pages == 0 || pages > MEM_CREATE_MAX_PAGES {
+        return Err(SysError::InvalidArgument);
+    }
+
+    let frames = (0..pages)
+        .map(|_| Frame::alloc())
+        .collect::<Result<_, _>>()?;
+    let object = KObject::Memory(SharedFrames::new(frames));
+    Ok(sched::SCHEDULER
+        .lock()
+        .running_process()
+        .handles
+        .insert(object, Rights::MEMORY))
+}
+
+/// a1: process handle. Returns its exit status once it has exited, see `ExitStatus::encode`.
+/// The status stays around until the last handle to the process is closed
+fn process_wait(handle: usize) -> SysResult {
+    let mut sch = sched::SCHEDULER.lock();
+    let pid = match sch.running_process().handles.get(handle, Rights::WAIT)?.object {
+        KObject::Process(pid) => pid,
+        _ => return Err(SysError::InvalidArgument),
+    };
+
+    if pid == sch.running_pid() {
+        return Err(SysError::InvalidArgument);
+    }
+    Ok(pid)
+}
+
+/// a1: process handle, or 0 for the caller, a2: priority level, 0 being the most urgent.
+/// Needs the `set_priority` capability
+fn set_priority(handle: usize, priority: usize) -> SysResult {
+    let mut sch = sched::SCHEDULER.lock();
+    let proc = sch.running_process();
+    if !proc.caps.set_priority {
+        return Err(SysError::Denied);
+    }
+
+    let pid = if handle == 0 {
+        sch.running_pid()
+    } else {
+        match proc.handles.get(handle, Rights::empty())?.object {
+            KObject::Process(pid) => pid,
+            _ => return Err(SysError::InvalidArgument),
+        }
+    };
+    sch.set_priority(pid, priority)?;
+    Ok(0)
+}
+
+/// a1: runtime, a2: period, both in ticks of the `time` CSR. Makes the caller a deadline process
+/// getting `runtime` out of every `period`, or a best-effort one again with a zero runtime.
+/// Needs the `reserve` capability, and fails with `Busy` if the CPU is already committed.
+/// Periods are limited to a few seconds
+fn reserve(runtime: usize, period: usize) -> SysResult {
+    let mut sch = sched::SCHEDULER.lock();
+    if !sch.running_process().caps.reserve {
+        return Err(SysError::Denied);
+    }
+
+    let pid = sch.running_pid();
+    sch.reserve(pid, runtime, period)?;
+    Ok(0)
+}
+
+/// a1: hart id, `PIN_ANY` or `PIN_NONE`. Dedicates a hart to the caller, which polls there
+/// without being preempted, or gives it back. Returns the hart, or 0 when unpinning.
+/// Needs the `pin_hart` capability
+fn pin_hart(hart: usize) -> SysResult {
+    let mut sch = sched::SCHEDULER.lock();
+    if !sch.running_process().caps.pin_hart {
+        return Err(SysError::Denied);
+    }
+
+    let pid = sch.running_pid();
+    match hart {
+        PIN_NONE => {
+            sch.unpin(pid);
+            Ok(0)
+        }
+        PIN_ANY => sch.pin(pid, None),
+        hart => sch.pin(pid, Some(hart)),
+    }
+}
+
+/// a1: process handle, or 0 for the caller, a2: buffer, a3: its size.
+/// Copies out CPU time and scheduling counters, truncated to the buffer. Returns their full size
+/// Needs the `stats` capability
+fn proc_info(handle: usize, buf: usize, len: usize) -> SysResult {
+    let mut sch = sched::SCHEDULER.lock();
+    if !sch.running_process().caps.stats {
+        return Err(SysError::Denied);
+    }
+
+    let pid = if handle == 0 {
+        sch.running_pid()
+    } else {
+        match sch.running_process().handles.get(handle, Rights::empty())?.object {
+            KObject::Process(pid) => pid,
+            _ => return Err(SysError::InvalidArgument),
+        }
+    };
+
+    // Gone already if it exited
+    let info = sch.info(pid).ok_or(SysError::NotFound)?;
+    let bytes = info.as_bytes();
+    let bytes = &bytes[..len.min(bytes.len())];
+    if !sch.running_process().mset.write_user(VirtAddr(buf), bytes) {
+        return Err(SysError::InvalidArgument);
+    }
+    Ok(info.as_bytes().len())
+}
+
+/// Prints the counters of every process, pending timers and memory use. Needs the `stats`
+/// capability
+fn proc_dump() -> SysResult {
+    if !sched::SCHEDULER.lock().running_process().caps.stats {
+        return Err(SysError::Denied);
+    }
+
+    sched::dump();
+    timer::dump();
+    mem::dump();
+    Ok(0)
+}
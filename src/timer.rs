@@ -1,23 +1,273 @@
-use crate::{sbi::set_timer, trap::TrapFrame};
-use riscv::register::{sie, time};
+//! Timer interrupts: the end of the running process's slice on each hart, and kernel timers
+//!
+//! Every hart programs its timer for whichever comes first, its slice running out or the earliest
+//! kernel timer. Any hart may fire a kernel timer, whichever gets to it first. Harts with nothing
+//! to preempt run tickless, see `Sched`. Harts dedicated to a process only take that process's
+//! timers, so they aren't interrupted on behalf of everybody else.
+//!
+//! Harts with the Sstc extension program their timer through `stimecmp` themselves, the others
+//! ask the SBI, which costs an ecall into firmware every time. Building with the `timer-sbi`
+//! feature always goes through the SBI, to compare the two with `proc_dump`. Both take far less
+//! than a tick of `time`, so the overhead is counted in cycles.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::consts::MAX_HARTS;
+use crate::{fdt::Fdt, hart, mprintln, sbi, trap::TrapFrame, uprintln};
+use riscv::register::{scounteren, sie, time};
 
 pub static mut TICKS: usize = 0;
 
-// TODO: Allow changing TIMEBASE
-static TIMEBASE: usize = 10_000_000;
-static SLICE: usize = TIMEBASE / 100;
+/// Ticks of the `time` CSR per second. What QEMU's virt machine uses, unless the device tree
+/// says otherwise
+static TIMEBASE: AtomicUsize = AtomicUsize::new(10_000_000);
+
+/// Bit mask of the harts with Sstc
+static SSTC: AtomicUsize = AtomicUsize::new(0);
+
+const CSR_STIMECMP: usize = 0x14D;
+
+// Trap handler for `stimecmp_works`: skip the faulting `csrw` and clear t1
+core::arch::global_asm!(
+    ".pushsection .text",
+    ".balign 4",
+    "stimecmp_probe_trap:",
+    "csrr t3, sepc",
+    "addi t3, t3, 4",
+    "csrw sepc, t3",
+    "li t1, 0",
+    "sret",
+    ".popsection",
+);
+
+extern "C" {
+    fn stimecmp_probe_trap();
+}
+
+/// Whether this hart can write `stimecmp`. Even with Sstc it can't unless the firmware set
+/// `menvcfg.STCE`, and the write traps as an illegal instruction. Runs before there is a stack to
+/// trap onto, so with a trap handler of its own
+fn stimecmp_works() -> bool {
+    let works: usize;
+    unsafe {
+        core::arch::asm!(
+            "csrrw t0, stvec, t0",
+            "li t1, 1",
+            // Never fires
+            "csrw {csr}, t2",
+            "csrw stvec, t0",
+            csr = const CSR_STIMECMP,
+            inout("t0") stimecmp_probe_trap as usize => _,
+            out("t1") works,
+            in("t2") usize::MAX,
+            out("t3") _,
+        );
+    }
+    works != 0
+}
+
+/// Take the timebase, and which harts have Sstc, from the device tree at `fdt_addr`. Has to run
+/// before anything reads the firmware's memory as free
+pub fn init_from_fdt(fdt_addr: usize) {
+    let fdt = match unsafe { Fdt::from_addr(fdt_addr) } {
+        Some(fdt) => fdt,
+        None => {
+            mprintln!("[Timer] No device tree, assuming a timebase of {} without Sstc", timebase());
+            return;
+        }
+    };
+
+    match fdt.property("/cpus", "timebase-frequency").and_then(crate::fdt::read_cells) {
+        Some(timebase) if timebase != 0 => TIMEBASE.store(timebase, Ordering::Relaxed),
+        _ => mprintln!("[Timer] No timebase in the device tree, assuming {}", timebase()),
+    }
+
+    if cfg!(not(feature = "timer-sbi")) {
+        let sstc = (0..MAX_HARTS)
+            .filter(|&hart| fdt.has_extension(hart, "sstc"))
+            .fold(0, |mask, hart| mask | 1 << hart);
+        SSTC.store(sstc, Ordering::Relaxed);
+    }
+}
+
+// Overhead of timer interrupts, in cycles, summed over all harts
+static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+static INTERRUPT_TIME: AtomicUsize = AtomicUsize::new(0);
+static PROGRAMS: AtomicUsize = AtomicUsize::new(0);
+static PROGRAM_TIME: AtomicUsize = AtomicUsize::new(0);
+
+fn cycles() -> usize {
+    let cycles: usize;
+    unsafe {
+        core::arch::asm!("rdcycle {}", out(reg) cycles);
+    }
+    cycles
+}
+
+/// Fire the timer interrupt of this hart once `rtc` reaches `deadline`, clearing any pending one
+fn set_timer(deadline: usize) {
+    let start = cycles();
+    if SSTC.load(Ordering::Relaxed) & (1 << hart::id()) != 0 {
+        unsafe {
+            core::arch::asm!("csrw {csr}, {0}", in(reg) deadline, csr = const CSR_STIMECMP);
+        }
+    } else {
+        sbi::set_timer(deadline);
+    }
+    PROGRAMS.fetch_add(1, Ordering::Relaxed);
+    PROGRAM_TIME.fetch_add(cycles() - start, Ordering::Relaxed);
+}
+
+/// Print how long timer interrupts and programming the timer take on average
+pub fn dump() {
+    let interrupts = INTERRUPTS.load(Ordering::Relaxed);
+    let programs = PROGRAMS.load(Ordering::Relaxed);
+    uprintln!(
+        "[Timer] sstc on {:#b}: {} interrupts, {} cycles each; {} programmed, {} cycles each",
+        SSTC.load(Ordering::Relaxed),
+        interrupts,
+        INTERRUPT_TIME.load(Ordering::Relaxed) / interrupts.max(1),
+        programs,
+        PROGRAM_TIME.load(Ordering::Relaxed) / programs.max(1),
+    );
+}
+
+pub fn timebase() -> usize {
+    TIMEBASE.load(Ordering::Relaxed)
+}
+
+/// 10 ms
+fn slice() -> usize {
+    timebase() / 100
+}
+
+/// Starts tickless, `Sched` arms the slice once there is something to preempt
 pub fn init() {
+    let bit = 1 << hart::id();
+    if SSTC.load(Ordering::Relaxed) & bit != 0 && !stimecmp_works() {
+        mprintln!("[Timer] Sstc on hart {} but stimecmp is off, using the SBI", hart::id());
+        SSTC.fetch_and(!bit, Ordering::Relaxed);
+    }
+
     unsafe {
         TICKS = 0;
         sie::set_stimer();
+        // User services read `time` directly, e.g. for timeouts
+        scounteren::set_tm();
+    }
+}
+
+/// A pending kernel timer: its deadline, then the order timers were added in
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct TimerId(usize, usize);
+
+/// Runs from the timer interrupt, with no locks held
+pub type Callback = Box<dyn FnOnce(TimerId) + Send>;
+
+struct Timers {
+    /// With the pid of the process each one is for
+    pending: BTreeMap<TimerId, (usize, Callback)>,
+    next_seq: usize,
+    /// When the slice on each hart runs out, `usize::MAX` if it doesn't
+    slice_end: [usize; MAX_HARTS],
+    /// The process each hart is dedicated to, see `Sched::pin`
+    dedicated: [Option<usize>; MAX_HARTS],
+}
+
+impl Timers {
+    /// Set the timer of this hart for whatever is due first
+    fn program(&self) {
+        let mut pending = self.pending.iter();
+        let earliest = match self.dedicated[hart::id()] {
+            Some(pid) => pending.find(|(_, &(owner, _))| owner == pid),
+            None => pending.next(),
+        };
+        let earliest = earliest.map_or(usize::MAX, |(id, _)| id.0);
+        set_timer(self.slice_end[hart::id()].min(earliest));
+    }
+
+    /// Have `id` program its timer again, which it does when it takes the IPI
+    fn reprogram(&self, id: usize) {
+        if id == hart::id() {
+            self.program();
+        } else {
+            REPROGRAM.fetch_or(1 << id, Ordering::SeqCst);
+            hart::kick(id);
+        }
     }
-    rearm();
 }
 
-// TODO: Remove me
+lazy_static! {
+    static ref TIMERS: Mutex<Timers> = Mutex::new(Timers {
+        pending: BTreeMap::new(),
+        next_seq: 0,
+        slice_end: [usize::MAX; MAX_HARTS],
+        dedicated: [None; MAX_HARTS],
+    });
+}
+
+/// Bit mask of the harts that have to program their timer again
+static REPROGRAM: AtomicUsize = AtomicUsize::new(0);
+
+/// Start a full slice on this hart
 pub fn rearm() {
-    let next = rtc() + SLICE;
-    set_timer(next);
+    let mut timers = TIMERS.lock();
+    timers.slice_end[hart::id()] = rtc() + slice();
+    timers.program();
+}
+
+/// No more preemption on this hart until the next `rearm`. Kernel timers still fire
+pub fn disarm() {
+    let mut timers = TIMERS.lock();
+    timers.slice_end[hart::id()] = usize::MAX;
+    timers.program();
+}
+
+/// Call `callback` for the process `pid` once `rtc` reaches `deadline`
+pub fn add(deadline: usize, pid: usize, callback: Callback) -> TimerId {
+    let mut timers = TIMERS.lock();
+    let id = TimerId(deadline, timers.next_seq);
+    timers.next_seq += 1;
+    timers.pending.insert(id, (pid, callback));
+    let here = hart::id();
+    match timers.dedicated[here] {
+        // Not for this hart, hand it to one that takes everybody's
+        Some(dedicated) if dedicated != pid => {
+            let general = (0..MAX_HARTS)
+                .find(|&id| hart::is_online(id) && timers.dedicated[id].is_none());
+            if let Some(general) = general {
+                timers.reprogram(general);
+            }
+        }
+        _ => timers.program(),
+    }
+    id
+}
+
+/// Hart `id` was dedicated to `pid`, or given back if None. It only takes that process's timers
+pub fn dedicate(id: usize, pid: Option<usize>) {
+    let mut timers = TIMERS.lock();
+    timers.dedicated[id] = pid;
+    timers.reprogram(id);
+}
+
+/// An IPI arrived. Program the timer again if that is what it was for
+pub fn on_ipi() {
+    let bit = 1 << hart::id();
+    if REPROGRAM.fetch_and(!bit, Ordering::SeqCst) & bit != 0 {
+        TIMERS.lock().program();
+    }
+}
+
+/// Returns false if the timer fired already
+pub fn cancel(id: TimerId) -> bool {
+    // The hart it was programmed on gets a spurious interrupt at worst
+    TIMERS.lock().pending.remove(&id).is_some()
 }
 
 pub fn rtc() -> usize {
@@ -25,12 +275,37 @@ pub fn rtc() -> usize {
 }
 
 pub fn now() -> usize {
-    rtc() / TIMEBASE
+    rtc() / timebase()
 }
 
 pub fn tick(tf: &mut TrapFrame) {
-    crate::mprintln!("Timer triggered at {} ({})", now(), rtc());
-    rearm();
+    let start = cycles();
+    let now = rtc();
+    INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    let (expired, preempt) = {
+        let mut timers = TIMERS.lock();
+        let later = timers.pending.split_off(&TimerId(now + 1, 0));
+        let expired = core::mem::replace(&mut timers.pending, later);
+        let preempt = timers.slice_end[hart::id()] <= now;
+        if preempt {
+            // Until `Sched` starts another one
+            timers.slice_end[hart::id()] = usize::MAX;
+        }
+        timers.program();
+        (expired, preempt)
+    };
 
-    crate::sched::tick(tf, true);
+    // Not under the timer lock: callbacks take the scheduler lock, which is taken before it
+    let fired = !expired.is_empty();
+    for (id, (_, callback)) in expired {
+        callback(id);
+    }
+
+    if preempt {
+        crate::sched::tick(tf, true);
+    } else if fired {
+        // An idle hart picks up whatever was woken right away
+        crate::sched::kick(tf);
+    }
+    INTERRUPT_TIME.fetch_add(cycles() - start, Ordering::Relaxed);
 }
@@ -2,9 +2,8 @@ use riscv::register::scause::{self, Exception, Interrupt, Scause, Trap};
 use riscv::register::sstatus::Sstatus;
 use riscv::register::{sie, sscratch, sstatus, stvec};
 
-use crate::mem::addr::{PhysAddr, VirtAddr};
-use crate::mem::set::{MapArea, MapPermission};
-use crate::{mprintln, sched, service, uprint};
+use crate::sched::ExitStatus;
+use crate::{mprintln, sched, syscall};
 
 #[repr(C)]
 #[derive(Clone)]
@@ -58,7 +57,7 @@ pub unsafe extern "C" fn trap_entry() -> ! {
         ".align 4",
         "csrrw sp, sscratch, sp",
 
-        "addi sp, sp, -{}",
+        "addi sp, sp, -{0}",
         save_reg!(x1, 1),
         save_reg!(x3, 3),
         save_reg!(x4, 4),
@@ -102,6 +101,9 @@ pub unsafe extern "C" fn trap_entry() -> ! {
         save_reg!(s3, 34),
         save_reg!(s4, 35),
 
+        // The hart id sits right above the trap frame. User tp is saved with the rest
+        "ld tp, {0}(sp)",
+
         "mv a0, sp",
         "call trap_impl",
         "j trap_exit",
@@ -162,16 +164,54 @@ pub unsafe extern "C" fn trap_exit() -> ! {
     )
 }
 
+/// Where kernel processes return to, with their exit code in a0. Enters the kernel like a trap
+/// would, since the process stack goes away with the process
+#[no_mangle]
+#[naked]
+pub unsafe extern "C" fn kernel_exit() -> ! {
+    core::arch::asm!(
+        "csrci sstatus, 2",
+        "csrrw sp, sscratch, x0",
+        "addi sp, sp, -{0}",
+        "ld tp, {0}(sp)",
+        "mv a1, sp",
+        "call kernel_exit_impl",
+        "j trap_exit",
+
+        const core::mem::size_of::<TrapFrame>(),
+        options(noreturn),
+    )
+}
+
+#[no_mangle]
+unsafe fn kernel_exit_impl(code: usize, tf: *mut TrapFrame) {
+    sched::exit_running(&mut *tf, ExitStatus::Exited(code as u32));
+}
+
 #[no_mangle]
 unsafe fn trap_impl(tf: *mut TrapFrame) {
     let tf = &mut *tf;
     mprintln!("[Trap] enter <- {:#x}", tf.sepc);
+    sched::stats::trap_enter(tf.sstatus.spp() == sstatus::SPP::User);
     match tf.scause.cause() {
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
             crate::timer::tick(tf);
         }
+        Trap::Interrupt(Interrupt::SupervisorSoft) => {
+            // The riscv crate only reads sip
+            core::arch::asm!("csrc sip, {}", in(reg) 1 << 1);
+            crate::timer::on_ipi();
+            sched::kick(tf);
+        }
         Trap::Exception(Exception::UserEnvCall) => {
-            syscall(tf);
+            syscall::syscall(tf);
+        }
+        Trap::Exception(e) if tf.sstatus.spp() == sstatus::SPP::User => {
+            mprintln!(
+                "[Trap] killing process on {:?} at {:#x}, tval = {:#x}",
+                e, tf.sepc, tf.stval
+            );
+            sched::exit_running(tf, ExitStatus::Faulted(tf.scause.bits()));
         }
         x => {
             panic!(
@@ -180,6 +220,7 @@ unsafe fn trap_impl(tf: *mut TrapFrame) {
             );
         }
     }
+    sched::stats::trap_exit();
     mprintln!("[Trap] exit -> {:#x}", tf.sepc);
 }
 
@@ -190,6 +231,8 @@ pub fn init() {
 
         // sstatus::set_sie();
         sie::set_sext();
+        // Other harts kick this one when a process pinned to it wakes up
+        sie::set_ssoft();
     }
 }
 
@@ -199,44 +242,3 @@ pub fn wfi() {
         riscv::asm::wfi();
     }
 }
-
-fn syscall(tf: &mut TrapFrame) {
-    mprintln!("[SyncSyscall] num: {}", tf.x[10]);
-    match tf.x[10] {
-        0x3 => {
-            let srv = tf.x[11];
-            mprintln!("[SyncSyscall] requesting service {}", srv);
-            if srv >= service::SERVICE_LIST.len() {
-                panic!("[SyncSyscall] invalid service {}", srv);
-            }
-
-            let (req, resp) = service::SERVICE_LIST[srv]();
-            let mut sch = sched::SCHEDULER.lock();
-            let proc = sch.running_process();
-            let req_area = MapArea::linear(
-                req.floor()..PhysAddr(req.0 + 1).ceil(),
-                VirtAddr(0x64000000).into(),
-                MapPermission::U | MapPermission::W | MapPermission::R,
-            );
-            let resp_area = MapArea::linear(
-                resp.floor()..PhysAddr(resp.0 + 1).ceil(),
-                VirtAddr(0x64001000).into(),
-                MapPermission::U | MapPermission::W | MapPermission::R,
-            );
-            proc.mset.push(req_area, None);
-            proc.mset.push(resp_area, None);
-            unsafe {
-                riscv::asm::sfence_vma_all();
-            }
-            tf.x[10] = 0x64000000;
-            tf.x[11] = 0x64001000;
-        }
-        0x100 => {
-            // Sync call putchar
-            uprint!("{}", tf.x[11] as u8 as char);
-        }
-        _ => {}
-    }
-
-    tf.sepc += 4;
-}
@@ -0,0 +1,250 @@
+#ifndef CHANNEL_H
+#define CHANNEL_H
+
+// Mirrors src/channel.rs
+
+#include <stdint.h>
+
+#define CHANNEL_MAGIC 0x4e414843u // "CHAN"
+#define CHANNEL_VERSION 1
+#define CHANNEL_PAGE_SIZE 4096
+
+struct ring_indices {
+  uint32_t head;
+  uint32_t tail;
+};
+
+struct channel_header {
+  uint32_t magic;
+  uint32_t version;
+  uint32_t capacity;
+  uint32_t req_size;
+  uint32_t resp_size;
+
+  struct ring_indices req;
+  struct ring_indices resp;
+
+  uint8_t client_sleeping;
+  uint8_t server_sleeping;
+  uint8_t closed;
+};
+
+struct message {
+  uint64_t ident;
+  uint64_t arg;
+};
+
+#define OP_NOP 0
+#define OP_WRITE 1
+#define OP_READ 2
+#define OP_TIMEOUT 3
+#define OP_WRITE_BUF 4
+
+#define SQE_SKIP_CQE (1 << 0)
+#define SQE_INLINE_MAX 48
+
+struct sqe {
+  uint8_t opcode;
+  uint8_t flags;
+  uint16_t _pad;
+  uint32_t len;
+  uint64_t user_data;
+  uint64_t args[6];
+};
+
+struct cqe {
+  uint64_t user_data;
+  int64_t res;
+  uint64_t value;
+};
+
+static inline uint8_t sqe_payload(const struct sqe *sqe, uint32_t idx) {
+  return (uint8_t) (sqe->args[idx / 8] >> (idx % 8 * 8));
+}
+
+// Posted to a service's accept channel for every new connection
+struct channel_accept {
+  uint64_t base;
+  uint64_t handle; // Channel handle
+  uint64_t process; // Handle to the client process
+  uint64_t pid;
+};
+
+// What the caller expects of a channel. The header is shared with the peer, which can rewrite it
+// at any time, so the helpers below only ever index the rings with these
+struct channel_layout {
+  uint32_t cap;
+  uint32_t req_size;
+  uint32_t resp_size;
+};
+
+// Capacities of ConsoleChannel and AcceptChannel
+#define CONSOLE_CAP 64
+#define ACCEPT_CAP 64
+
+static const struct channel_layout console_layout = { CONSOLE_CAP, sizeof(struct sqe), sizeof(struct cqe) };
+static const struct channel_layout accept_layout = { ACCEPT_CAP, sizeof(struct channel_accept), sizeof(struct message) };
+
+static inline uint64_t channel_slots_len(uint32_t cap, uint32_t size) {
+  uint64_t len = (uint64_t) cap * size;
+  return (len + CHANNEL_PAGE_SIZE - 1) / CHANNEL_PAGE_SIZE * CHANNEL_PAGE_SIZE;
+}
+
+static inline char *channel_req_slots(struct channel_header *h) {
+  return (char *) h + CHANNEL_PAGE_SIZE;
+}
+
+static inline char *channel_resp_slots(struct channel_header *h, const struct channel_layout *l) {
+  return channel_req_slots(h) + channel_slots_len(l->cap, l->req_size);
+}
+
+static inline int channel_valid(struct channel_header *h, const struct channel_layout *l) {
+  return h->magic == CHANNEL_MAGIC
+    && h->version == CHANNEL_VERSION
+    && h->capacity == l->cap
+    && h->req_size == l->req_size
+    && h->resp_size == l->resp_size;
+}
+
+// Plain byte copy: there is no libc to provide memcpy
+static inline void channel_copy(volatile char *dst, const volatile char *src, uint32_t size) {
+  for(uint32_t i = 0; i < size; ++i) dst[i] = src[i];
+}
+
+static inline int ring_push(struct ring_indices *idx, char *slots, uint32_t cap, uint32_t size, const void *val) {
+  uint32_t tail = __atomic_load_n(&idx->tail, __ATOMIC_RELAXED);
+  if(tail - __atomic_load_n(&idx->head, __ATOMIC_ACQUIRE) >= cap) return 0;
+  channel_copy(slots + (uint64_t) (tail % cap) * size, val, size);
+  __atomic_store_n(&idx->tail, tail + 1, __ATOMIC_RELEASE);
+  return 1;
+}
+
+static inline int ring_pop(struct ring_indices *idx, char *slots, uint32_t cap, uint32_t size, void *val) {
+  uint32_t head = __atomic_load_n(&idx->head, __ATOMIC_RELAXED);
+  if(__atomic_load_n(&idx->tail, __ATOMIC_ACQUIRE) == head) return 0;
+  channel_copy(val, slots + (uint64_t) (head % cap) * size, size);
+  __atomic_store_n(&idx->head, head + 1, __ATOMIC_RELEASE);
+  return 1;
+}
+
+// Provided by the vDSO. Channels are passed by handle; a zero handle waits on every channel
+extern int64_t channel_wait(uint64_t handle);
+// Deadlines are in ticks of the time CSR. Returns ERR_TIMED_OUT once it passes; 0 waits forever
+#define ERR_TIMED_OUT -9
+extern int64_t channel_wait_until(uint64_t handle, uint64_t deadline);
+// With DOORBELL_HANDOFF, a sleeping peer runs right away for the rest of the caller's slice
+#define DOORBELL_HANDOFF (1 << 0)
+extern int64_t channel_doorbell(uint64_t handle, uint64_t flags);
+
+#define GRANT_R (1 << 1)
+#define GRANT_W (1 << 2)
+#define GRANT_X (1 << 3)
+
+// Lends whole pages to the peer. Returns where they are mapped there, or a negative error
+extern int64_t channel_grant(uint64_t handle, uint64_t start, uint64_t pages, uint64_t perm);
+extern int64_t channel_revoke(uint64_t handle, uint64_t peer_base);
+// 0 if the peer lent us all of [addr, addr + len) for reading through the channel
+extern int64_t channel_lent(uint64_t handle, uint64_t addr, uint64_t len);
+
+#define RIGHT_READ (1 << 0)
+#define RIGHT_WRITE (1 << 1)
+#define RIGHT_MAP (1 << 2)
+#define RIGHT_SIGNAL (1 << 3)
+#define RIGHT_WAIT (1 << 4)
+#define RIGHT_DUPLICATE (1 << 5)
+#define RIGHT_TRANSFER (1 << 6)
+
+extern int64_t handle_close(uint64_t handle);
+// Channel handles can't be duplicated. They can be transferred while no pages are lent through them
+extern int64_t handle_dup(uint64_t handle, uint64_t rights);
+extern int64_t handle_transfer(uint64_t chan, uint64_t handle, uint64_t rights);
+extern int64_t handle_map(uint64_t handle);
+extern int64_t handle_unmap(uint64_t addr);
+// Fails with ERR_NO_MEMORY once physical memory runs out, as can connecting and mapping
+#define ERR_NO_MEMORY -10
+extern int64_t mem_create(uint64_t pages);
+
+// Set in the status of processes killed on a fault, with the cause in the low bits
+#define EXIT_FAULTED (1ull << 32)
+
+// Returning from _start exits too, with its return value
+extern void exit(uint64_t code) __attribute__((noreturn));
+// Blocks until the process exits and returns its status. It can be asked until the handle is closed
+extern int64_t process_wait(uint64_t handle);
+// Lets other ready processes run first, if there are any
+extern void yield_now();
+// Lower levels run first. Needs a capability; a zero handle means the caller
+extern int64_t set_priority(uint64_t handle, uint64_t priority);
+// Runtime per period in ticks of the time CSR. Needs a capability; a zero runtime drops it
+extern int64_t sched_reserve(uint64_t runtime, uint64_t period);
+
+#define PIN_ANY (~0ull)
+#define PIN_NONE (~0ull - 1)
+
+// Returns the hart the caller polls on from now on, without preemption. Needs a capability
+extern int64_t sched_pin_hart(uint64_t hart);
+
+// Ticks of the time CSR per second
+extern uint64_t timebase();
+extern void sleep_until(uint64_t deadline);
+extern void sleep_for(uint64_t ticks);
+
+// Times are in ticks of the time CSR
+struct proc_info {
+  uint64_t pid;
+  uint64_t state; // 0 ready, 1 running, 2 blocked, 3 exited
+  uint64_t priority;
+  uint64_t user_time;
+  uint64_t kernel_time;
+  uint64_t voluntary;   // Switches on blocking, yielding or handing off
+  uint64_t involuntary; // Preemptions
+  uint64_t wakeups;
+  uint64_t wakeup_latency; // Summed over all wakeups
+  uint64_t max_wakeup_latency;
+  uint64_t parent; // 0 for the kernel
+};
+
+// Both need a capability. A zero handle means the caller. Returns the size of the full struct
+extern int64_t proc_info(uint64_t handle, struct proc_info *info, uint64_t len);
+// Prints the counters of every process to the console
+extern int64_t proc_dump();
+
+static inline int channel_closed(struct channel_header *h) {
+  return __atomic_load_n(&h->closed, __ATOMIC_SEQ_CST);
+}
+
+static inline void channel_set_server_sleeping(struct channel_header *h, uint8_t sleeping) {
+  __atomic_store_n(&h->server_sleeping, sleeping, __ATOMIC_SEQ_CST);
+}
+
+static inline int channel_client_sleeping(struct channel_header *h) {
+  return __atomic_load_n(&h->client_sleeping, __ATOMIC_SEQ_CST);
+}
+
+// Service side
+static inline int channel_req_pending(struct channel_header *h) {
+  return __atomic_load_n(&h->req.tail, __ATOMIC_SEQ_CST) != __atomic_load_n(&h->req.head, __ATOMIC_RELAXED);
+}
+
+static inline int channel_recv(struct channel_header *h, const struct channel_layout *l, void *req) {
+  return ring_pop(&h->req, channel_req_slots(h), l->cap, l->req_size, req);
+}
+
+static inline int channel_can_reply(struct channel_header *h, const struct channel_layout *l) {
+  return __atomic_load_n(&h->resp.tail, __ATOMIC_RELAXED) - __atomic_load_n(&h->resp.head, __ATOMIC_ACQUIRE) < l->cap;
+}
+
+static inline int channel_reply(struct channel_header *h, const struct channel_layout *l, const void *resp) {
+  return ring_push(&h->resp, channel_resp_slots(h, l), l->cap, l->resp_size, resp);
+}
+
+// Client side
+static inline int channel_send(struct channel_header *h, const struct channel_layout *l, const void *req) {
+  return ring_push(&h->req, channel_req_slots(h), l->cap, l->req_size, req);
+}
+
+static inline int channel_poll(struct channel_header *h, const struct channel_layout *l, void *resp) {
+  return ring_pop(&h->resp, channel_resp_slots(h, l), l->cap, l->resp_size, resp);
+}
+
+#endif
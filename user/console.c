@@ -0,0 +1,232 @@
+#include <stdint.h>
+#include "channel.h"
+
+#define MAX_CLIENTS 64
+
+struct client {
+  struct channel_header *chan;
+  uint64_t handle;
+  uint64_t process;
+};
+
+static struct client clients[MAX_CLIENTS];
+static int client_cnt = 0;
+
+static void set_sleeping(struct channel_header *accept, uint8_t sleeping) {
+  channel_set_server_sleeping(accept, sleeping);
+  for(int i = 0; i < client_cnt; ++i) channel_set_server_sleeping(clients[i].chan, sleeping);
+}
+
+static int any_pending(struct channel_header *accept) {
+  if(channel_req_pending(accept)) return 1;
+  for(int i = 0; i < client_cnt; ++i)
+    if(channel_req_pending(clients[i].chan)) return 1;
+  return 0;
+}
+
+#define ERR_INVALID_ARGUMENT -2
+#define ERR_BUSY -5
+#define ERR_UNSUPPORTED -7
+
+#define UART_LSR 5
+#define UART_LSR_DR 1
+
+// 2ms out of every 10ms
+#define RESERVE_RUNTIME_US 2000
+#define RESERVE_PERIOD_US 10000
+
+// Rounds without any work before a pinned console goes back to sleeping on doorbells
+#define POLL_IDLE_MAX (1 << 16)
+
+#define MAX_TIMEOUTS 64
+
+struct timeout {
+  struct channel_header *chan; // 0 if the slot is free
+  uint64_t handle;
+  uint64_t user_data;
+  uint64_t deadline;
+};
+
+static struct timeout timeouts[MAX_TIMEOUTS];
+static int timeout_cnt = 0;
+
+static inline uint64_t rdtime() {
+  uint64_t t;
+  asm volatile("rdtime %0" : "=r"(t));
+  return t;
+}
+
+static void add_timeout(const struct client *client, const struct sqe *sqe, struct cqe *cqe) {
+  for(int i = 0; i < MAX_TIMEOUTS; ++i) {
+    if(timeouts[i].chan) continue;
+    timeouts[i].chan = client->chan;
+    timeouts[i].handle = client->handle;
+    timeouts[i].user_data = sqe->user_data;
+    uint64_t now = rdtime();
+    // Saturate, a wrapped deadline would fire right away
+    timeouts[i].deadline = sqe->args[0] > UINT64_MAX - now ? UINT64_MAX : now + sqe->args[0];
+    ++timeout_cnt;
+    return;
+  }
+  cqe->res = ERR_BUSY;
+}
+
+static int fire_timeouts() {
+  int fired = 0;
+  uint64_t now = rdtime();
+  for(int i = 0; i < MAX_TIMEOUTS; ++i) {
+    struct timeout *t = &timeouts[i];
+    if(!t->chan || t->deadline > now || !channel_can_reply(t->chan, &console_layout)) continue;
+    struct cqe cqe = { t->user_data, 0, 0 };
+    channel_reply(t->chan, &console_layout, &cqe);
+    if(channel_client_sleeping(t->chan)) channel_doorbell(t->handle, 0);
+    t->chan = 0;
+    --timeout_cnt;
+    fired = 1;
+  }
+  return fired;
+}
+
+// Earliest deadline of a pending timeout, 0 if there is none
+static uint64_t next_deadline() {
+  uint64_t next = 0;
+  for(int i = 0; i < MAX_TIMEOUTS; ++i)
+    if(timeouts[i].chan && (!next || timeouts[i].deadline < next)) next = timeouts[i].deadline;
+  return next;
+}
+
+static void drop_timeouts(struct channel_header *chan) {
+  for(int i = 0; i < MAX_TIMEOUTS; ++i) {
+    if(timeouts[i].chan != chan) continue;
+    timeouts[i].chan = 0;
+    --timeout_cnt;
+  }
+}
+
+// Returns 0 if the completion is posted later
+static int handle(const struct client *client, volatile uint8_t *serial, const struct sqe *sqe, struct cqe *cqe) {
+  cqe->user_data = sqe->user_data;
+  cqe->res = 0;
+  cqe->value = 0;
+
+  switch(sqe->opcode) {
+  case OP_NOP:
+    break;
+  case OP_WRITE:
+    if(sqe->len > SQE_INLINE_MAX) {
+      cqe->res = ERR_INVALID_ARGUMENT;
+      break;
+    }
+    for(uint32_t i = 0; i < sqe->len; ++i) *serial = sqe_payload(sqe, i);
+    cqe->res = sqe->len;
+    break;
+  case OP_WRITE_BUF: {
+    // Lent by the client, unless it lied about the address
+    if(channel_lent(client->handle, sqe->args[0], sqe->len) < 0) {
+      cqe->res = ERR_INVALID_ARGUMENT;
+      break;
+    }
+    const volatile uint8_t *buf = (const volatile uint8_t *) sqe->args[0];
+    for(uint32_t i = 0; i < sqe->len; ++i) *serial = buf[i];
+    cqe->res = sqe->len;
+    break;
+  }
+  case OP_READ:
+    if(sqe->len > 8) {
+      cqe->res = ERR_INVALID_ARGUMENT;
+      break;
+    }
+    while(cqe->res < sqe->len && (serial[UART_LSR] & UART_LSR_DR)) {
+      cqe->value |= (uint64_t) serial[0] << (cqe->res * 8);
+      ++cqe->res;
+    }
+    break;
+  case OP_TIMEOUT:
+    if(sqe->flags & SQE_SKIP_CQE) break;
+    add_timeout(client, sqe, cqe);
+    return cqe->res != 0;
+  default:
+    cqe->res = ERR_UNSUPPORTED;
+  }
+  return 1;
+}
+
+void _start(struct channel_header *accept, uint64_t accept_handle, uint64_t serial_handle) {
+  if(!channel_valid(accept, &accept_layout)) while(1);
+
+  int64_t serial_addr = handle_map(serial_handle);
+  if(serial_addr < 0) while(1);
+  volatile uint8_t *serial = (volatile uint8_t *) serial_addr;
+
+  // Poll on a hart of our own if one is spare, otherwise reserve time on the shared ones.
+  // Best-effort if the CPU is committed already
+  int pinned = sched_pin_hart(PIN_ANY) >= 0;
+  uint64_t hz = timebase();
+  if(!pinned) sched_reserve(hz * RESERVE_RUNTIME_US / 1000000, hz * RESERVE_PERIOD_US / 1000000);
+
+  int idle_rounds = 0;
+  while(1) {
+    int progress = fire_timeouts();
+
+    struct channel_accept conn;
+    while(channel_recv(accept, &accept_layout, &conn)) {
+      progress = 1;
+      struct channel_header *chan = (struct channel_header *) conn.base;
+      if(client_cnt == MAX_CLIENTS || !channel_valid(chan, &console_layout)) {
+        handle_close(conn.handle);
+        handle_close(conn.process);
+        continue;
+      }
+      clients[client_cnt].chan = chan;
+      clients[client_cnt].handle = conn.handle;
+      clients[client_cnt].process = conn.process;
+      ++client_cnt;
+    }
+
+    for(int i = 0; i < client_cnt; ++i) {
+      struct client *client = &clients[i];
+      if(channel_closed(client->chan)) {
+        // Client is gone, release our end
+        drop_timeouts(client->chan);
+        handle_close(client->handle);
+        handle_close(client->process);
+        clients[i--] = clients[--client_cnt];
+        continue;
+      }
+
+      int drained = 0;
+      struct sqe sqe;
+      struct cqe cqe;
+      // Leave entries queued until there is room for their completion
+      while(channel_can_reply(client->chan, &console_layout) && channel_recv(client->chan, &console_layout, &sqe)) {
+        if(handle(client, serial, &sqe, &cqe) && !(sqe.flags & SQE_SKIP_CQE)) channel_reply(client->chan, &console_layout, &cqe);
+        drained = 1;
+      }
+
+      // The client may be waiting for ring space or a completion. Hand it the CPU, we are done
+      // with it for now
+      if(drained && channel_client_sleeping(client->chan)) channel_doorbell(client->handle, DOORBELL_HANDOFF);
+      progress |= drained;
+    }
+
+    if(progress) {
+      idle_rounds = 0;
+      continue;
+    }
+    // Our sleeping flags are clear, so clients keep queueing without ringing
+    if(pinned && ++idle_rounds < POLL_IDLE_MAX) continue;
+    idle_rounds = 0;
+
+    uint64_t deadline = next_deadline();
+    // Due already, but the client has no room for the completion yet
+    if(deadline && deadline <= rdtime()) {
+      yield_now();
+      continue;
+    }
+
+    // Idle: arm every channel, recheck, and sleep until some client rings or a timeout is due
+    set_sleeping(accept, 1);
+    if(!any_pending(accept)) channel_wait_until(0, deadline);
+    set_sleeping(accept, 0);
+  }
+}
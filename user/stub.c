@@ -2,3 +2,27 @@
 
 uint64_t kernel_meow() {}
 void putchar(char c) {}
+int64_t putchar_wait(char c) {}
+int64_t console_write(const char *buf, uint64_t len) {}
+int64_t channel_wait(uint64_t handle) {}
+int64_t channel_wait_until(uint64_t handle, uint64_t deadline) {}
+int64_t channel_doorbell(uint64_t handle, uint64_t flags) {}
+int64_t channel_grant(uint64_t handle, uint64_t start, uint64_t pages, uint64_t perm) {}
+int64_t channel_revoke(uint64_t handle, uint64_t peer_base) {}
+int64_t handle_close(uint64_t handle) {}
+int64_t handle_dup(uint64_t handle, uint64_t rights) {}
+int64_t handle_transfer(uint64_t chan, uint64_t handle, uint64_t rights) {}
+int64_t handle_map(uint64_t handle) {}
+int64_t handle_unmap(uint64_t addr) {}
+int64_t mem_create(uint64_t pages) {}
+void exit(uint64_t code) { while(1); }
+int64_t process_wait(uint64_t handle) {}
+void yield_now() {}
+int64_t set_priority(uint64_t handle, uint64_t priority) {}
+int64_t sched_reserve(uint64_t runtime, uint64_t period) {}
+int64_t sched_pin_hart(uint64_t hart) {}
+uint64_t timebase() {}
+void sleep_until(uint64_t deadline) {}
+void sleep_for(uint64_t ticks) {}
+int64_t proc_info(uint64_t handle, void *info, uint64_t len) {}
+int64_t proc_dump() {}
@@ -1,6 +1,8 @@
 #include <stdint.h>
 
 extern void putchar(char c);
+extern int64_t putchar_wait(char c);
+extern int64_t console_write(const char *buf, uint64_t len);
 
 static char *hw = "Hello world!\n";
 
@@ -17,10 +19,18 @@ void putint(uint64_t n) {
     buf[0] = '0';
   } else --top;
 
-  for(; top >= 0; --top) putchar(buf[top]);
-  putchar('\n');
+  char line[33];
+  int len = 0;
+  for(; top >= 0; --top) line[len++] = buf[top];
+  line[len++] = '\n';
+  // One batch per line, returns once the service has written it
+  console_write(line, len);
 }
 
-void _start() {
-  for(uint64_t i = 0;; ++i) putint(i);
+#define LINES 1000
+
+// Returns into the vDSO, which exits with the return value
+int _start() {
+  for(uint64_t i = 0; i < LINES; ++i) putint(i);
+  return 0;
 }